@@ -0,0 +1,167 @@
+use crate::{utils, Bangle, FileInfo, FileKind};
+use anyhow::{Context, Result};
+use std::io::{Cursor, Read, Write};
+
+/// Archive entry listing the saved files.
+const MANIFEST: &str = "manifest.json";
+/// Directory of the archive holding the files' contents.
+const FILES: &str = "files/";
+
+/// Which files to take : all of them when there is no include pattern.
+pub struct Selection {
+    include: Vec<glob::Pattern>,
+    exclude: Vec<glob::Pattern>,
+}
+
+impl Selection {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        let patterns = |patterns: &[String]| -> Result<Vec<glob::Pattern>> {
+            Ok(patterns
+                .iter()
+                .map(|p| glob::Pattern::new(p))
+                .collect::<Result<_, _>>()?)
+        };
+        Ok(Selection {
+            include: patterns(include)?,
+            exclude: patterns(exclude)?,
+        })
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.lists(name))
+            && !self.exclude.iter().any(|p| p.matches(name))
+    }
+
+    /// Is given file explicitly included ?
+    pub fn lists(&self, name: &str) -> bool {
+        self.include.iter().any(|p| p.matches(name))
+    }
+}
+
+/// Archives are zip files when their name says so, tar files otherwise.
+fn is_zip(archive: &str) -> bool {
+    archive.to_lowercase().ends_with(".zip")
+}
+
+/// What a restore did with each selected file.
+#[derive(Debug, Default)]
+pub struct Restored {
+    /// Uploaded files.
+    pub files: Vec<FileInfo>,
+    /// Empty files, the watch can't store them.
+    pub skipped: Vec<String>,
+    /// Files the watch didn't take, with the reason.
+    pub failed: Vec<(String, anyhow::Error)>,
+}
+
+/// Save the watch's files (StorageFiles included) with a manifest into given archive,
+/// returning the manifest.
+pub async fn backup(
+    bangle: &Bangle,
+    archive: &str,
+    selection: &Selection,
+) -> Result<Vec<FileInfo>> {
+    let mut manifest = Vec::new();
+    let mut entries = Vec::new();
+    for mut info in bangle.list_file_infos(false).await? {
+        if !selection.contains(&info.name) {
+            continue;
+        }
+        let content = bangle
+            .read_file(&info.name)
+            .await
+            .with_context(|| format!("failed saving {}", info.name))?;
+        info.size = content.len();
+        info.crc = Some(crc32fast::hash(&content));
+        entries.push((format!("{FILES}{}", info.name), content));
+        manifest.push(info);
+    }
+    entries.insert(
+        0,
+        (MANIFEST.to_string(), serde_json::to_vec_pretty(&manifest)?),
+    );
+    let content = if is_zip(archive) {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (path, data) in &entries {
+            zip.start_file(path.as_str(), zip::write::SimpleFileOptions::default())?;
+            zip.write_all(data)?;
+        }
+        zip.finish()?.into_inner()
+    } else {
+        let mut tar = tar::Builder::new(Vec::new());
+        for (path, data) in &entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, path, data.as_slice())?;
+        }
+        tar.into_inner()?
+    };
+    utils::save_file(archive, &content).await?;
+    Ok(manifest)
+}
+
+/// Upload back the files saved in given archive.
+///
+/// Nothing is uploaded unless all selected files are intact in the archive.
+pub async fn restore(bangle: &Bangle, archive: &str, selection: &Selection) -> Result<Restored> {
+    let content = utils::read_file(archive).await?;
+    let mut entries = if is_zip(archive) {
+        let mut zip = zip::ZipArchive::new(Cursor::new(content))?;
+        (0..zip.len())
+            .map(|i| {
+                let mut file = zip.by_index(i)?;
+                let mut data = Vec::new();
+                file.read_to_end(&mut data)?;
+                Ok((file.name()?.into_owned(), data))
+            })
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        tar::Archive::new(content.as_slice())
+            .entries()?
+            .map(|entry| {
+                let mut entry = entry?;
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data)?;
+                Ok((entry.path()?.to_string_lossy().into_owned(), data))
+            })
+            .collect::<Result<Vec<_>>>()?
+    };
+    let mut take = |path: &str| {
+        entries
+            .iter()
+            .position(|(p, _)| p == path)
+            .map(|i| entries.swap_remove(i).1)
+            .with_context(|| format!("{path} missing from {archive}"))
+    };
+    let manifest: Vec<FileInfo> = serde_json::from_slice(&take(MANIFEST)?)?;
+    let files = manifest
+        .into_iter()
+        .filter(|info| selection.contains(&info.name))
+        .map(|info| {
+            let content = take(&format!("{FILES}{}", info.name))?;
+            anyhow::ensure!(
+                content.len() == info.size && Some(crc32fast::hash(&content)) == info.crc,
+                "{} is corrupted in {archive}",
+                info.name
+            );
+            Ok((info, content))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut restored = Restored::default();
+    for (info, content) in files {
+        if content.is_empty() {
+            restored.skipped.push(info.name);
+            continue;
+        }
+        let result = match info.kind {
+            FileKind::Plain => bangle.write_file(&info.name, &content).await,
+            FileKind::StorageFile => bangle.write_storage_file(&info.name, &content).await,
+        };
+        match result {
+            Ok(()) => restored.files.push(info),
+            Err(e) => restored.failed.push((info.name, e)),
+        }
+    }
+    Ok(restored)
+}
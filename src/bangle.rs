@@ -0,0 +1,707 @@
+use crate::heatshrink;
+use crate::network::{self, Communicator};
+use crate::transport::Transport;
+use anyhow::{Context, Result};
+use base64::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Longest name of a plain file, StorageFiles take one char less.
+pub const MAX_FILENAME_LEN: usize = 28;
+/// Bytes written by each `Storage.write` of an upload.
+const UPLOAD_CHUNK_SIZE: usize = 1024;
+/// Don't bother compressing smaller chunks.
+const MIN_COMPRESSED_SIZE: usize = 128;
+/// Bytes sent back in each line of a download (a multiple of 3 to avoid base64 padding).
+const DOWNLOAD_CHUNK_SIZE: usize = 768;
+/// Times we send or fetch again corrupted chunks before giving up.
+const MAX_RETRIES: usize = 3;
+
+/// How long we wait by default for the watch to say something.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The watch stays silent while compacting its storage, for a while when it is full.
+const COMPACT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// An event of the watch's calendar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CalendarEvent {
+    pub title: String,
+    pub description: Option<String>,
+    /// Start time, in seconds since the epoch.
+    pub timestamp: i64,
+}
+
+/// Javascript string literal holding given text, whatever quotes or backslashes it contains.
+fn js_string(text: &str) -> String {
+    serde_json::to_string(text).expect("strings always serialize")
+}
+
+/// Javascript expression evaluating to given bytes, as short as we can.
+fn encode_chunk(chunk: &[u8]) -> String {
+    let compressed = heatshrink::compress(chunk);
+    // decompressing costs time and memory on the watch, it must be worth it
+    if chunk.len() >= MIN_COMPRESSED_SIZE && compressed.len() * 10 < chunk.len() * 8 {
+        format!(
+            "require(\"heatshrink\").decompress(atob(\"{}\"))",
+            BASE64_STANDARD.encode(compressed)
+        )
+    } else {
+        format!("atob(\"{}\")", BASE64_STANDARD.encode(chunk))
+    }
+}
+
+/// How a file is stored on the watch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileKind {
+    /// Written at once with `Storage.write`.
+    Plain,
+    /// Written little by little with `Storage.open`, stored in numbered chunks.
+    StorageFile,
+}
+
+impl FileKind {
+    /// Code opening given file, defining its `size`.
+    fn open(self, filename: &str) -> String {
+        match self {
+            FileKind::Plain => format!(
+                "let s = require(\"Storage\").read({});\
+if (s === undefined) throw new Error(\"File not found\");\
+let size = s.length;",
+                js_string(filename)
+            ),
+            FileKind::StorageFile => format!(
+                "let f = require(\"Storage\").open({}, \"r\");\
+let size = f.getLength();\
+if (size === 0) throw new Error(\"File not found\");",
+                js_string(filename)
+            ),
+        }
+    }
+
+    /// Code running `action` on each chunk `c` of the file opened by `open`.
+    fn for_each_chunk(self, chunk_size: usize, action: &str) -> String {
+        self.for_each_chunk_while(chunk_size, "true", action)
+    }
+
+    /// Like `for_each_chunk`, stopping as soon as `condition` is false.
+    fn for_each_chunk_while(self, chunk_size: usize, condition: &str, action: &str) -> String {
+        match self {
+            FileKind::Plain => format!(
+                "for (let i = 0; i < size && ({condition}); i += {chunk_size}) {{let c = s.substr(i, {chunk_size}); {action}}}"
+            ),
+            FileKind::StorageFile => format!(
+                "let c = f.read({chunk_size}); while (c !== undefined && ({condition})) {{{action} c = f.read({chunk_size});}}"
+            ),
+        }
+    }
+}
+
+/// A file stored on the watch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileInfo {
+    pub name: String,
+    pub kind: FileKind,
+    /// In bytes.
+    pub size: usize,
+    /// CRC32 of the content, only for plain files and when asked for.
+    pub crc: Option<u32>,
+}
+
+/// Occupation of the watch's flash, as given by `Storage.getStats`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageStats {
+    pub total_bytes: usize,
+    /// Never written since last compaction.
+    pub free_bytes: usize,
+    pub file_bytes: usize,
+    pub file_count: usize,
+    /// Taken by erased or replaced files, reclaimed by compaction.
+    pub trash_bytes: usize,
+    pub trash_count: usize,
+}
+
+/// Follows file transfers.
+pub trait Progress: Send + Sync {
+    /// `done` bytes out of `total` went through for the transfer of given file.
+    fn update(&self, name: &str, done: usize, total: usize);
+}
+
+/// Code writing given chunk at given offset, creating a file of given size if any.
+fn write_chunk(filename: &str, chunk: &[u8], offset: usize, size: Option<usize>) -> String {
+    let size = size.map(|size| format!(", {size}")).unwrap_or_default();
+    format!(
+        "require(\"Storage\").write({}, {}, {offset}{size});",
+        js_string(filename),
+        encode_chunk(chunk)
+    )
+}
+
+/// Turn bytes moving through the link into progress of given transfer,
+/// `to_file_bytes` converting all bytes moved so far into bytes of the file.
+fn reporter(
+    progress: Arc<dyn Progress>,
+    name: &str,
+    total: usize,
+    to_file_bytes: impl Fn(usize) -> usize + Send + Sync + 'static,
+) -> network::OnProgress {
+    let name = name.to_string();
+    let moved = AtomicUsize::new(0);
+    Arc::new(move |bytes| {
+        let moved = moved.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        progress.update(&name, to_file_bytes(moved).min(total), total);
+    })
+}
+
+/// High level client for a banglejs watch.
+pub struct Bangle {
+    comms: Arc<Communicator>,
+    timeout: Duration,
+    /// Check transfers with the watch's CRC32.
+    verify: bool,
+    progress: Option<Arc<dyn Progress>>,
+}
+
+impl Bangle {
+    /// Start talking to the watch on the other end of given transport.
+    pub fn new(transport: Box<dyn Transport>) -> Self {
+        let comms = Arc::new(Communicator::new(transport));
+        // spawn the receiver
+        let recv_comms = comms.clone();
+        tokio::task::spawn(async {
+            if let Err(e) = network::receive_messages(recv_comms).await {
+                eprintln!("{e:#}");
+            }
+        });
+        Bangle {
+            comms,
+            timeout: DEFAULT_TIMEOUT,
+            verify: true,
+            progress: None,
+        }
+    }
+
+    /// Fail operations when the watch stays silent for more than given duration.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Enable (default) or disable checking that transferred files arrived intact.
+    pub fn with_verification(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Report the progress of transfers to given observer.
+    pub fn with_progress(mut self, progress: Arc<dyn Progress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Stop code running on the watch, for example after cancelling an operation.
+    pub async fn interrupt(&self) -> Result<()> {
+        self.comms.interrupt().await
+    }
+
+    /// Subscribe to everything the watch displays on its console.
+    pub fn console(&self) -> broadcast::Receiver<String> {
+        self.comms.console()
+    }
+
+    pub async fn disconnect(&self) -> Result<()> {
+        self.comms.disconnect().await
+    }
+
+    /// Plain files, without StorageFiles' chunks.
+    pub async fn list_files(&self) -> Result<Vec<String>> {
+        self.comms
+            .request(
+                "require(\"Storage\").list(undefined, {sf: false}).forEach(f => reply(f));",
+                self.timeout,
+            )
+            .await
+    }
+
+    pub async fn list_storage_files(&self) -> Result<Vec<String>> {
+        self.comms
+            .request(
+                "require(\"Storage\").list(undefined, {sf: true}).forEach(f => reply(f));",
+                self.timeout,
+            )
+            .await
+    }
+
+    pub async fn read_file(&self, filename: &str) -> Result<Vec<u8>> {
+        let kind = self.file_kind(filename).await?;
+        let mut content = self.download(filename, kind).await?;
+        if !self.verify {
+            return content.with_context(|| format!("{filename} was damaged on the way"));
+        }
+        let mut retries = 0;
+        loop {
+            let corrupted = match &content {
+                Some(content) => {
+                    self.corrupted_chunks(filename, kind, content, DOWNLOAD_CHUNK_SIZE)
+                        .await?
+                }
+                None => None,
+            };
+            // only downloaded content gets checked
+            if corrupted.as_ref().is_some_and(Vec::is_empty) {
+                return Ok(content.unwrap_or_default());
+            }
+            anyhow::ensure!(
+                retries < MAX_RETRIES,
+                "{filename} is still corrupted after {MAX_RETRIES} retries"
+            );
+            retries += 1;
+            let (Some(data), Some(corrupted)) = (
+                content.as_mut(),
+                corrupted.filter(|_| kind == FileKind::Plain),
+            ) else {
+                // we lost whole lines (or can't seek), start again
+                content = self.download(filename, kind).await?;
+                continue;
+            };
+            for offset in corrupted {
+                let msg = format!(
+                    "reply(btoa(require(\"Storage\").read({}, {offset}, {DOWNLOAD_CHUNK_SIZE})));",
+                    js_string(filename)
+                );
+                let chunk = self.comms.request(&msg, self.timeout).await?.concat();
+                // a damaged chunk is left for the next check
+                if let Ok(chunk) = BASE64_STANDARD.decode(chunk.trim()) {
+                    let end = (offset + DOWNLOAD_CHUNK_SIZE).min(data.len());
+                    data.splice(offset..end, chunk);
+                }
+            }
+        }
+    }
+
+    /// Beginning of given file holding (at least) its first `lines` lines,
+    /// the watch stops sending once they are there.
+    ///
+    /// Only damaged lines are sent again, the data is not checked against a CRC.
+    pub async fn read_lines(&self, filename: &str, lines: usize) -> Result<Vec<u8>> {
+        let kind = self.file_kind(filename).await?;
+        let msg = format!(
+            "{}let n = {lines};{}",
+            kind.open(filename),
+            kind.for_each_chunk_while(
+                DOWNLOAD_CHUNK_SIZE,
+                "n > 0",
+                "reply(btoa(c)); n -= c.split(\"\\n\").length - 1;"
+            )
+        );
+        let mut retries = 0;
+        loop {
+            let replies = self.comms.request(&msg, self.timeout).await?;
+            let content: Option<Vec<Vec<u8>>> = replies
+                .iter()
+                .map(|line| BASE64_STANDARD.decode(line.trim()).ok())
+                .collect();
+            if let Some(content) = content {
+                return Ok(content.concat());
+            }
+            anyhow::ensure!(
+                retries < MAX_RETRIES,
+                "{filename} is still corrupted after {MAX_RETRIES} retries"
+            );
+            retries += 1;
+        }
+    }
+
+    /// All files with their sizes, computing CRCs of plain files on the watch if asked to.
+    pub async fn list_file_infos(&self, with_crc: bool) -> Result<Vec<FileInfo>> {
+        let crc = if with_crc { "E.CRC32(s)" } else { "null" };
+        let msg = format!(
+            "let st = require(\"Storage\");\
+st.list(undefined, {{sf: false}}).forEach(f => {{let s = st.read(f); reply(JSON.stringify([f, 0, s.length, {crc}]));}});\
+st.list(undefined, {{sf: true}}).forEach(f => reply(JSON.stringify([f, 1, st.open(f, \"r\").getLength(), null])));"
+        );
+        self.comms
+            .request(&msg, self.timeout)
+            .await?
+            .iter()
+            .map(|line| {
+                let (name, kind, size, crc): (String, u8, usize, Option<i64>) =
+                    serde_json::from_str(line)?;
+                Ok(FileInfo {
+                    name,
+                    kind: if kind == 0 {
+                        FileKind::Plain
+                    } else {
+                        FileKind::StorageFile
+                    },
+                    size,
+                    // espruino may display it as a signed integer
+                    crc: crc.map(|crc| crc as u32),
+                })
+            })
+            .collect()
+    }
+
+    /// Is given file a plain file or a StorageFile ?
+    pub async fn file_kind(&self, filename: &str) -> Result<FileKind> {
+        let msg = format!(
+            "reply(require(\"Storage\").read({0}) === undefined && \
+require(\"Storage\").list(undefined, {{sf: true}}).indexOf({0}) >= 0);",
+            js_string(filename)
+        );
+        let storage_file = self.comms.request(&msg, self.timeout).await?.concat();
+        Ok(if storage_file.trim() == "true" {
+            FileKind::StorageFile
+        } else {
+            FileKind::Plain
+        })
+    }
+
+    /// Size of given file, in bytes.
+    pub async fn file_size(&self, filename: &str) -> Result<usize> {
+        let kind = self.file_kind(filename).await?;
+        self.size(filename, kind).await
+    }
+
+    async fn size(&self, filename: &str, kind: FileKind) -> Result<usize> {
+        let msg = format!("{}reply(size);", kind.open(filename));
+        let size = self.comms.request(&msg, self.timeout).await?.concat();
+        Ok(size.trim().parse()?)
+    }
+
+    /// Content of given file, `None` if a line was damaged on the way.
+    async fn download(&self, filename: &str, kind: FileKind) -> Result<Option<Vec<u8>>> {
+        // base64 lines are much shorter than one decimal per byte
+        let msg = format!(
+            "{}{}",
+            kind.open(filename),
+            kind.for_each_chunk(DOWNLOAD_CHUNK_SIZE, "reply(btoa(c));")
+        );
+        let lines = match self.progress.clone() {
+            Some(progress) => {
+                let total = self.size(filename, kind).await?;
+                // 4 base64 chars for 3 bytes
+                let reporter = reporter(progress, filename, total, |chars| chars * 3 / 4);
+                self.comms
+                    .request_with_progress(&msg, self.timeout, reporter)
+                    .await?
+            }
+            None => self.comms.request(&msg, self.timeout).await?,
+        };
+        let mut content = Vec::new();
+        for line in lines {
+            let Ok(data) = BASE64_STANDARD.decode(line.trim()) else {
+                return Ok(None);
+            };
+            content.extend(data);
+        }
+        Ok(Some(content))
+    }
+
+    pub async fn write_file(&self, filename: &str, content: &[u8]) -> Result<()> {
+        anyhow::ensure!(
+            filename.len() <= MAX_FILENAME_LEN,
+            "this filename is too large (max {MAX_FILENAME_LEN} chars)"
+        );
+        anyhow::ensure!(!content.is_empty(), "empty file");
+        let file_size = content.len();
+        let msg = content.chunks(UPLOAD_CHUNK_SIZE).enumerate().fold(
+            String::from("\x10"),
+            |mut msg, (index, chunk)| {
+                let offset = index * UPLOAD_CHUNK_SIZE;
+                // the first write creates the file with its final size
+                let size = (offset == 0).then_some(file_size);
+                msg.push_str(&write_chunk(filename, chunk, offset, size));
+                msg
+            },
+        );
+        let mut retries = 0;
+        loop {
+            self.send_reporting(filename, &msg, content.len()).await?;
+            if !self.verify {
+                return Ok(());
+            }
+            let corrupted = self
+                .corrupted_chunks(filename, FileKind::Plain, content, UPLOAD_CHUNK_SIZE)
+                .await?;
+            // written flash can't be fixed in place, we create the file again
+            if corrupted.is_some_and(|corrupted| corrupted.is_empty()) {
+                return Ok(());
+            }
+            anyhow::ensure!(
+                retries < MAX_RETRIES,
+                "{filename} is still corrupted after {MAX_RETRIES} retries"
+            );
+            retries += 1;
+        }
+    }
+
+    /// Write given content as a StorageFile, like the ones opened by apps in append mode.
+    pub async fn write_storage_file(&self, filename: &str, content: &[u8]) -> Result<()> {
+        // the last char is used to number the chunks
+        anyhow::ensure!(
+            filename.len() < MAX_FILENAME_LEN,
+            "this filename is too large (max {} chars)",
+            MAX_FILENAME_LEN - 1
+        );
+        anyhow::ensure!(!content.is_empty(), "empty file");
+        // erased flash marks the end of the file
+        anyhow::ensure!(
+            !content.contains(&0xff),
+            "StorageFiles can't contain byte 255"
+        );
+        let mut msg = String::from("\x10(function(f){");
+        for chunk in content.chunks(UPLOAD_CHUNK_SIZE) {
+            write!(
+                &mut msg,
+                "f.write(atob(\"{}\"));",
+                BASE64_STANDARD.encode(chunk)
+            )
+            .ok();
+        }
+        write!(
+            &mut msg,
+            "}})(require(\"Storage\").open({}, \"w\"));",
+            js_string(filename)
+        )
+        .ok();
+        let mut retries = 0;
+        loop {
+            self.send_reporting(filename, &msg, content.len()).await?;
+            if !self.verify
+                || self
+                    .corrupted_chunks(filename, FileKind::StorageFile, content, UPLOAD_CHUNK_SIZE)
+                    .await?
+                    .is_some_and(|corrupted| corrupted.is_empty())
+            {
+                return Ok(());
+            }
+            anyhow::ensure!(
+                retries < MAX_RETRIES,
+                "{filename} is still corrupted after {MAX_RETRIES} retries"
+            );
+            retries += 1;
+        }
+    }
+
+    /// Offsets of the chunks of the file on the watch which don't match given content,
+    /// `None` if the sizes differ or CRCs were lost on the way.
+    async fn corrupted_chunks(
+        &self,
+        filename: &str,
+        kind: FileKind,
+        content: &[u8],
+        chunk_size: usize,
+    ) -> Result<Option<Vec<usize>>> {
+        let msg = format!(
+            "{}reply(size);{}",
+            kind.open(filename),
+            kind.for_each_chunk(chunk_size, "reply(E.CRC32(c));")
+        );
+        let response = self.comms.request(&msg, self.timeout).await?;
+        let Some((size, crcs)) = response.split_first() else {
+            anyhow::bail!("no size returned");
+        };
+        if size.trim().parse::<usize>().ok() != Some(content.len())
+            || crcs.len() != content.len().div_ceil(chunk_size)
+        {
+            return Ok(None);
+        }
+        let mut corrupted = Vec::new();
+        for (index, (chunk, crc)) in content.chunks(chunk_size).zip(crcs).enumerate() {
+            // espruino may display it as a signed integer, a damaged one marks its chunk
+            if Some(crc32fast::hash(chunk)) != crc.trim().parse::<i64>().ok().map(|c| c as u32) {
+                corrupted.push(index * chunk_size);
+            }
+        }
+        Ok(Some(corrupted))
+    }
+
+    /// Check with the watch's CRC32 that given file holds given content,
+    /// even when transfers are not verified.
+    pub async fn check_file(&self, filename: &str, content: &[u8]) -> Result<()> {
+        let kind = self.file_kind(filename).await?;
+        match self
+            .corrupted_chunks(filename, kind, content, UPLOAD_CHUNK_SIZE)
+            .await?
+        {
+            None => anyhow::bail!("{filename} does not have the expected size"),
+            Some(chunks) if !chunks.is_empty() => {
+                anyhow::bail!("{filename} differs at offsets {chunks:?}")
+            }
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Copy given file on the watch, plain file or StorageFile.
+    /// An existing destination is only replaced when forced to.
+    pub async fn copy(&self, from: &str, to: &str, force: bool) -> Result<()> {
+        self.duplicate(from, to, force, true).await
+    }
+
+    /// Rename given file on the watch, by copying then erasing it.
+    pub async fn rename(&self, from: &str, to: &str, force: bool) -> Result<()> {
+        self.duplicate(from, to, force, false).await
+    }
+
+    async fn duplicate(&self, from: &str, to: &str, force: bool, keep: bool) -> Result<()> {
+        anyhow::ensure!(from != to, "{from} and {to} are the same file");
+        let kind = self.file_kind(from).await?;
+        // StorageFiles use the last char to number their chunks
+        let max_len = match kind {
+            FileKind::Plain => MAX_FILENAME_LEN,
+            FileKind::StorageFile => MAX_FILENAME_LEN - 1,
+        };
+        anyhow::ensure!(
+            to.len() <= max_len,
+            "this filename is too large (max {max_len} chars)"
+        );
+        let (source, target) = (js_string(from), js_string(to));
+        let copy = match kind {
+            FileKind::Plain => format!("st.write({target}, s);"),
+            FileKind::StorageFile => format!(
+                "let t = st.open({target}, \"w\");{}",
+                kind.for_each_chunk(DOWNLOAD_CHUNK_SIZE, "t.write(c);")
+            ),
+        };
+        let erase_source = match (keep, kind) {
+            (true, _) => "",
+            (false, FileKind::Plain) => &format!("st.erase({source});"),
+            (false, FileKind::StorageFile) => "f.erase();",
+        };
+        let msg = format!(
+            "let st = require(\"Storage\");{open}\
+let exists = st.read({target}) !== undefined || st.list(undefined, {{sf: true}}).indexOf({target}) >= 0;\
+if (exists && !{force}) throw new Error({target} + \" already exists\");\
+if (exists && st.read({target}) === undefined) st.open({target}, \"r\").erase();\
+else if (exists) st.erase({target});\
+{copy}{erase_source}",
+            open = kind.open(from)
+        );
+        self.comms.request(&msg, self.timeout).await?;
+        Ok(())
+    }
+
+    /// Erase given file, plain file or StorageFile.
+    pub async fn erase(&self, filename: &str) -> Result<()> {
+        let msg = format!(
+            "\x10(function(s){{if (s.read({0}) === undefined) s.open({0}, \"r\").erase(); \
+else s.erase({0});}})(require(\"Storage\"));",
+            js_string(filename)
+        );
+        self.comms.send_message(&msg, self.timeout).await?;
+        Ok(())
+    }
+
+    /// How full the watch's flash is.
+    pub async fn storage_stats(&self) -> Result<StorageStats> {
+        Ok(serde_json::from_str(
+            &self.eval("require(\"Storage\").getStats()").await?,
+        )?)
+    }
+
+    /// Reclaim the space of erased files, waiting for the watch to finish.
+    /// Progress is reported in trash bytes, at the start and the end.
+    pub async fn compact(&self) -> Result<StorageStats> {
+        let trash = self.storage_stats().await?.trash_bytes;
+        if let Some(progress) = &self.progress {
+            progress.update("compact", 0, trash);
+        }
+        self.comms
+            .request(
+                "require(\"Storage\").compact();",
+                self.timeout.max(COMPACT_TIMEOUT),
+            )
+            .await?;
+        let stats = self.storage_stats().await?;
+        if let Some(progress) = &self.progress {
+            progress.update("compact", trash, trash);
+        }
+        Ok(stats)
+    }
+
+    /// Evaluate given javascript expression, returning its value as json.
+    pub async fn eval(&self, expression: &str) -> Result<String> {
+        let msg = format!("reply(JSON.stringify({expression}));");
+        self.comms
+            .request(&msg, self.timeout)
+            .await?
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("no value returned"))
+    }
+
+    /// Silently run given code, its output goes to the console.
+    pub async fn run(&self, code: &str) -> Result<()> {
+        let escaped_msg: String = code
+            .split_terminator('\n')
+            .fold(String::new(), |mut s, line| {
+                write!(&mut s, "\x10{}", line).ok();
+                s
+            });
+        self.send_reporting("code", &escaped_msg, code.len()).await
+    }
+
+    /// Send given message, reporting its progress as the transfer of given file of `size` bytes.
+    async fn send_reporting(&self, name: &str, msg: &str, size: usize) -> Result<()> {
+        match self.progress.clone() {
+            Some(progress) => {
+                // the encoded message is larger than the file, count its share of the file
+                let msg_len = msg.len().max(1);
+                let reporter = reporter(progress, name, size, move |bytes| bytes * size / msg_len);
+                self.comms
+                    .send_message_with_progress(msg, self.timeout, reporter)
+                    .await
+            }
+            None => self.comms.send_message(msg, self.timeout).await,
+        }
+    }
+
+    /// Send given code as if typed in the watch's REPL : it is echoed on the console
+    /// together with its value.
+    pub async fn write(&self, code: &str) -> Result<()> {
+        self.comms.send_message(code, self.timeout).await?;
+        Ok(())
+    }
+
+    /// Set the watch's clock, in seconds since the epoch.
+    pub async fn set_time(&self, timestamp: i64) -> Result<()> {
+        // setTime((new Date("Tue, 19 Feb 2019 10:57")).getTime()/1000)
+        let msg = format!("\x10setTime({});", timestamp);
+        self.comms.send_message(&msg, self.timeout).await?;
+        Ok(())
+    }
+
+    /// Replace all calendar events.
+    pub async fn set_calendar(&self, events: &[CalendarEvent]) -> Result<()> {
+        let mut msg = events
+            .iter()
+            .fold("\x10let e=[];".to_string(), |mut s, event| {
+                let (summary, time) = (&event.title, event.timestamp);
+                if let Some(location) = &event.description {
+                    write!(
+                        &mut s,
+                        "e.push({{title:{}, description: {}, timestamp: {time}}});",
+                        js_string(summary),
+                        js_string(location)
+                    )
+                    .ok();
+                } else {
+                    write!(
+                        &mut s,
+                        "e.push({{title:{}, timestamp: {time}}});",
+                        js_string(summary)
+                    )
+                    .ok();
+                };
+                s
+            });
+        msg.push_str("require(\"Storage\").writeJSON(\"android.calendar.json\", e);");
+        self.comms.send_message(&msg, self.timeout).await?;
+        Ok(())
+    }
+}
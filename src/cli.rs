@@ -1,6 +1,7 @@
 use std::str::FromStr;
 
-use clap::{Parser, Subcommand};
+use banglecomm::utils::STDIO;
+use clap::{Parser, Subcommand, ValueEnum};
 #[derive(Parser)]
 #[command(name = "BangleComm")]
 #[command(author = "frederic wagner <frederic.wagner@imag.fr>")]
@@ -14,6 +15,30 @@ pub struct Cli {
     /// Don't close connection when exiting.
     #[arg(short, long)]
     pub keep_connected: bool,
+    /// Talk to an espruino board on given serial device instead of bluetooth.
+    #[arg(short, long, conflicts_with_all = ["tcp", "spawn"])]
+    pub serial: Option<String>,
+    /// Baud rate of the serial device.
+    #[arg(short, long, default_value_t = 9600, requires = "serial")]
+    pub baud: u32,
+    /// Talk to an espruino (emulator) listening on given host:port instead of bluetooth.
+    #[arg(long, conflicts_with = "spawn")]
+    pub tcp: Option<String>,
+    /// Spawn given shell command (espruino linux build, emulator) and talk to it on its stdin/stdout.
+    #[arg(long)]
+    pub spawn: Option<String>,
+    /// Bluetooth watch to use : name (or part of it), address or alias. Remembered as the default.
+    #[arg(short, long, conflicts_with_all = ["serial", "tcp", "spawn"])]
+    pub device: Option<String>,
+    /// Remember the bluetooth watch we connect to under given alias.
+    #[arg(long, conflicts_with_all = ["serial", "tcp", "spawn"])]
+    pub alias: Option<String>,
+    /// Don't check with the watch that transferred files arrived intact.
+    #[arg(long)]
+    pub no_verify: bool,
+    /// Give up on an operation when the watch stays silent for this number of seconds.
+    #[arg(short, long, default_value_t = 10)]
+    pub timeout: u64,
 
     /// Command to execute.
     #[command(subcommand)]
@@ -22,26 +47,178 @@ pub struct Cli {
 
 #[derive(Subcommand, Clone, Debug)]
 pub enum Command {
-    /// Upload given file to the watch.
-    Put { filename: String },
-    /// Download given file from the watch.
-    Get { filename: String },
+    /// Upload given files to the watch.
+    Put {
+        /// Local files, - for stdin, or glob patterns.
+        /// `put <local> <remote>` uploads a single file under another name.
+        #[arg(required = true)]
+        local: Vec<String>,
+        /// Name on the watch of a single file, the local file's name by default.
+        #[arg(short, long)]
+        remote: Option<String>,
+        /// Store it as a StorageFile, like the ones apps append to.
+        #[arg(long)]
+        storage_file: bool,
+        /// Only display the files we would upload.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Download given files from the watch.
+    Get {
+        /// Name on the watch or a glob pattern.
+        remote: String,
+        /// Where to save it, - for stdout. The remote name in the current directory by default.
+        /// The directory where to save them for a pattern.
+        local: Option<String>,
+        /// Only display the files we would download.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Upload the files of given directory which changed since last time.
+    Sync {
+        directory: String,
+        /// Download the watch's files which changed instead.
+        #[arg(long)]
+        pull: bool,
+        /// Also erase files which don't exist on the other side.
+        #[arg(long)]
+        delete: bool,
+        /// Only sync files matching one of these glob patterns,
+        /// system files like .boot0 or setting.json only when they are given here.
+        #[arg(long)]
+        include: Vec<String>,
+        /// Don't sync files matching one of these glob patterns.
+        #[arg(long)]
+        exclude: Vec<String>,
+        /// Only display what we would do.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Save all the watch's files into a tar archive, or a zip one if named *.zip.
+    Backup {
+        /// Archive to create, - for stdout.
+        archive: String,
+        /// Only save files matching one of these glob patterns.
+        #[arg(long)]
+        include: Vec<String>,
+        /// Don't save files matching one of these glob patterns.
+        #[arg(long)]
+        exclude: Vec<String>,
+    },
+    /// Upload back the files of a backup archive.
+    Restore {
+        /// Archive to read, - for stdin.
+        archive: String,
+        /// Only restore files matching one of these glob patterns.
+        #[arg(long)]
+        include: Vec<String>,
+        /// Don't restore files matching one of these glob patterns.
+        #[arg(long)]
+        exclude: Vec<String>,
+    },
     /// Synchronize the watch with the local time.
     SyncClock,
     /// Add ical file's events as alarms.
     SyncCalendar { ical_filename: String },
     /// List files.
-    Ls,
+    Ls {
+        /// Only list files whose name matches this regular expression.
+        filter: Option<String>,
+        /// Display sizes and kinds.
+        #[arg(short, long)]
+        long: bool,
+        /// Group files by app id, the part of their name before the first dot.
+        #[arg(short, long)]
+        group: bool,
+        /// Order of the files.
+        #[arg(long, value_enum, default_value_t = SortKey::Name)]
+        sort: SortKey,
+        /// Display files as json.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Display how full the watch's flash is.
+    Df {
+        /// Display statistics as json.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Reclaim the space of erased files on the watch.
+    Compact,
     /// Close connection.
     Disconnect,
-    /// Erase given file.
-    Rm { filename: String },
+    /// Erase given files, names or glob patterns.
+    Rm {
+        #[arg(required = true)]
+        filenames: Vec<String>,
+        /// Only display the files we would erase.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Display given file of the watch, as text.
+    Cat { remote: String },
+    /// Display the first lines of given file of the watch.
+    Head {
+        remote: String,
+        /// Number of lines to display.
+        #[arg(short = 'n', long, default_value_t = 10)]
+        lines: usize,
+    },
+    /// Display given file of the watch in hexadecimal, for binary files.
+    Hexdump { remote: String },
+    /// Edit given file of the watch with $EDITOR.
+    Edit { remote: String },
+    /// Rename a file on the watch.
+    Mv {
+        from: String,
+        to: String,
+        /// Replace the destination if it exists.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Copy a file on the watch.
+    Cp {
+        from: String,
+        to: String,
+        /// Replace the destination if it exists.
+        #[arg(long)]
+        force: bool,
+    },
     /// Run given js string on the watch.
     Run { filename: String },
     /// Run given code line on the watch.
     Write { code: String },
     /// Compress then run given app code on the watch. Never exits.
     App { filename: String },
+    /// List espruino devices around, without connecting to them.
+    Scan {
+        /// Scan for this number of seconds.
+        #[arg(short, long, default_value_t = 5)]
+        duration: u64,
+        /// Display devices as json.
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortKey {
+    Name,
+    /// Largest files first.
+    Size,
+}
+
+impl Command {
+    /// Does this command send file contents (or json) to stdout ?
+    pub fn writes_to_stdout(&self) -> bool {
+        match self {
+            Command::Get { local, .. } => local.as_deref() == Some(STDIO),
+            Command::Backup { archive, .. } => archive == STDIO,
+            Command::Ls { json, .. } | Command::Df { json } => *json,
+            Command::Cat { .. } | Command::Head { .. } | Command::Hexdump { .. } => true,
+            _ => false,
+        }
+    }
 }
 
 impl FromStr for Command {
@@ -50,14 +227,113 @@ impl FromStr for Command {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut tokens = s.split_whitespace();
         let command_type = tokens.next().ok_or(())?;
-        let arg = tokens.next().unwrap_or_default().to_string();
+        let (flags, args): (Vec<&str>, Vec<&str>) = tokens.partition(|t| t.starts_with("--"));
+        let arg = |i: usize| args.get(i).map(|a| a.to_string());
+        let flag = |name: &str| flags.contains(&name);
+        // flags taking values are written --name=value
+        let values = |name: &str| {
+            flags
+                .iter()
+                .filter_map(|f| f.strip_prefix(name)?.strip_prefix('='))
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+        };
         match command_type {
-            "ls" => Ok(Command::Ls),
-            "put" => Ok(Command::Put { filename: arg }),
-            "get" => Ok(Command::Get { filename: arg }),
-            "rm" => Ok(Command::Rm { filename: arg }),
-            "run" => Ok(Command::Run { filename: arg }),
-            "app" => Ok(Command::App { filename: arg }),
+            "ls" => Ok(Command::Ls {
+                filter: args
+                    .iter()
+                    .find(|a| !a.starts_with('-'))
+                    .map(|a| a.to_string()),
+                long: flag("--long") || args.contains(&"-l"),
+                group: flag("--group") || args.contains(&"-g"),
+                sort: if flag("--sort=size") {
+                    SortKey::Size
+                } else {
+                    SortKey::Name
+                },
+                json: flag("--json"),
+            }),
+            "put" if !args.is_empty() => Ok(Command::Put {
+                local: args.iter().map(|a| a.to_string()).collect(),
+                remote: values("--remote").pop(),
+                storage_file: flag("--storage-file"),
+                dry_run: flag("--dry-run"),
+            }),
+            "get" => Ok(Command::Get {
+                remote: arg(0).ok_or(())?,
+                local: arg(1),
+                dry_run: flag("--dry-run"),
+            }),
+            "rm" if !args.is_empty() => Ok(Command::Rm {
+                filenames: args.iter().map(|a| a.to_string()).collect(),
+                dry_run: flag("--dry-run"),
+            }),
+            "sync" => Ok(Command::Sync {
+                directory: arg(0).ok_or(())?,
+                pull: flag("--pull"),
+                delete: flag("--delete"),
+                include: values("--include"),
+                exclude: values("--exclude"),
+                dry_run: flag("--dry-run"),
+            }),
+            "backup" => Ok(Command::Backup {
+                archive: arg(0).ok_or(())?,
+                include: values("--include"),
+                exclude: values("--exclude"),
+            }),
+            "restore" => Ok(Command::Restore {
+                archive: arg(0).ok_or(())?,
+                include: values("--include"),
+                exclude: values("--exclude"),
+            }),
+            "df" => Ok(Command::Df {
+                json: flag("--json"),
+            }),
+            "compact" => Ok(Command::Compact),
+            "cat" => Ok(Command::Cat {
+                remote: arg(0).ok_or(())?,
+            }),
+            "head" => match args.as_slice() {
+                ["-n", lines, remote] => Ok(Command::Head {
+                    remote: remote.to_string(),
+                    lines: lines.parse().map_err(|_| ())?,
+                }),
+                [remote] => Ok(Command::Head {
+                    remote: remote.to_string(),
+                    lines: 10,
+                }),
+                _ => Err(()),
+            },
+            "hexdump" => Ok(Command::Hexdump {
+                remote: arg(0).ok_or(())?,
+            }),
+            "edit" => Ok(Command::Edit {
+                remote: arg(0).ok_or(())?,
+            }),
+            "mv" => Ok(Command::Mv {
+                from: arg(0).ok_or(())?,
+                to: arg(1).ok_or(())?,
+                force: flag("--force"),
+            }),
+            "cp" => Ok(Command::Cp {
+                from: arg(0).ok_or(())?,
+                to: arg(1).ok_or(())?,
+                force: flag("--force"),
+            }),
+            "run" => Ok(Command::Run {
+                filename: arg(0).ok_or(())?,
+            }),
+            "app" => Ok(Command::App {
+                filename: arg(0).ok_or(())?,
+            }),
+            "scan" => Ok(Command::Scan {
+                duration: arg(0)
+                    .map(|d| d.parse())
+                    .transpose()
+                    .map_err(|_| ())?
+                    .unwrap_or(5),
+                json: flag("--json"),
+            }),
             _ => Err(()),
         }
     }
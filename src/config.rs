@@ -0,0 +1,36 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Watches we remember between runs.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Devices {
+    /// Address of the watch to use when none is given.
+    pub default: Option<String>,
+    /// Addresses by alias.
+    pub aliases: BTreeMap<String, String>,
+}
+
+impl Devices {
+    pub async fn load(path: &Path) -> Result<Self> {
+        match tokio::fs::read(path).await {
+            Ok(content) => Ok(serde_json::from_slice(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Devices::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        tokio::fs::write(path, serde_json::to_vec_pretty(self)?).await?;
+        Ok(())
+    }
+
+    /// What to look for : given name, address or alias, or else the default watch.
+    pub fn query(&self, device: Option<&str>) -> Option<String> {
+        match device {
+            Some(device) => Some(self.aliases.get(device).map_or(device, |a| a).to_string()),
+            None => self.default.clone(),
+        }
+    }
+}
@@ -0,0 +1,696 @@
+use crate::cli::SortKey;
+use anyhow::Result;
+use banglecomm::archive::Selection;
+use banglecomm::bangle::MAX_FILENAME_LEN;
+use banglecomm::{utils, Bangle, FileInfo, FileKind};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io::Write;
+use std::path::Path;
+
+/// App id of given file, the part of its name before the first dot.
+/// Empty for system files like `.boot0`.
+fn app_id(name: &str) -> &str {
+    name.split('.').next().unwrap_or_default()
+}
+
+/// Does given name contain glob wildcards ?
+fn is_pattern(name: &str) -> bool {
+    name.contains(['*', '?', '['])
+}
+
+/// Files on the watch matching given name or pattern.
+pub async fn remote_files(bangle: &Bangle, pattern: &str) -> Result<Vec<String>> {
+    if !is_pattern(pattern) {
+        return Ok(vec![pattern.to_string()]);
+    }
+    let pattern = glob::Pattern::new(pattern)?;
+    let mut files = bangle.list_files().await?;
+    files.extend(bangle.list_storage_files().await?);
+    files.retain(|f| pattern.matches(f));
+    files.sort();
+    Ok(files)
+}
+
+fn local_files(pattern: &str) -> Result<Vec<String>> {
+    if !is_pattern(pattern) {
+        return Ok(vec![pattern.to_string()]);
+    }
+    glob::glob(pattern)?
+        .map(|path| Ok(path?.to_string_lossy().into_owned()))
+        .collect()
+}
+
+/// Apply given operation to all files, with a summary when there are several.
+async fn for_each<'a, F, Fut>(
+    files: &'a [String],
+    action: &str,
+    dry_run: bool,
+    mut operation: F,
+) -> Result<()>
+where
+    F: FnMut(&'a str) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    anyhow::ensure!(!files.is_empty(), "no matching file");
+    if dry_run {
+        for file in files {
+            println!("would {action} {file}");
+        }
+        return Ok(());
+    }
+    if let [file] = files {
+        return operation(file).await;
+    }
+    let mut failures = 0;
+    for file in files {
+        match operation(file).await {
+            Ok(()) => println!("{action} {file}: ok"),
+            Err(e) => {
+                failures += 1;
+                println!("{action} {file}: failed: {e}");
+            }
+        }
+    }
+    println!("{} succeeded, {failures} failed", files.len() - failures);
+    anyhow::ensure!(failures == 0, "{failures} files failed");
+    Ok(())
+}
+
+pub async fn ls(
+    bangle: &Bangle,
+    filter: Option<&str>,
+    long: bool,
+    group: bool,
+    sort: SortKey,
+    json: bool,
+) -> Result<()> {
+    let filter = filter.map(regex::Regex::new).transpose()?;
+    let mut files = bangle.list_file_infos(false).await?;
+    files.retain(|f| {
+        filter
+            .as_ref()
+            .is_none_or(|filter| filter.is_match(&f.name))
+    });
+    match sort {
+        SortKey::Name => files.sort_by(|a, b| a.name.cmp(&b.name)),
+        SortKey::Size => files.sort_by(|a, b| b.size.cmp(&a.size).then(a.name.cmp(&b.name))),
+    }
+    if group {
+        // stable sort, files keep their order inside each app
+        files.sort_by(|a, b| app_id(&a.name).cmp(app_id(&b.name)));
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&files)?);
+        return Ok(());
+    }
+    for (i, file) in files.iter().enumerate() {
+        let app = app_id(&file.name);
+        if group && (i == 0 || app_id(&files[i - 1].name) != app) {
+            let (count, size) = files
+                .iter()
+                .filter(|f| app_id(&f.name) == app)
+                .fold((0, 0), |(count, size), f| (count + 1, size + f.size));
+            let app = if app.is_empty() { "(system)" } else { app };
+            println!("{app}: {count} files, {size} bytes");
+        }
+        let indent = if group { "  " } else { "" };
+        let storage_file = file.kind == FileKind::StorageFile;
+        if long {
+            let kind = if storage_file { "StorageFile" } else { "file" };
+            println!("{indent}{:>8} {kind:<11} {}", file.size, file.name);
+        } else if storage_file {
+            println!("{indent}{} (StorageFile)", file.name);
+        } else {
+            println!("{indent}{}", file.name);
+        }
+    }
+    Ok(())
+}
+
+/// Espruino strings are latin1, apps may still write utf-8.
+fn text(content: &[u8]) -> Cow<'_, str> {
+    match std::str::from_utf8(content) {
+        Ok(text) => Cow::Borrowed(text),
+        Err(_) => Cow::Owned(content.iter().map(|&b| b as char).collect()),
+    }
+}
+
+/// Print given file of the watch, only its first lines if asked to.
+pub async fn cat(bangle: &Bangle, remote: &str, lines: Option<usize>) -> Result<()> {
+    let content = match lines {
+        Some(lines) => bangle.read_lines(remote, lines).await?,
+        None => bangle.read_file(remote).await?,
+    };
+    let text = text(&content);
+    let text = match lines {
+        Some(lines) => text
+            .split_inclusive('\n')
+            .take(lines)
+            .collect::<String>()
+            .into(),
+        None => text,
+    };
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(text.as_bytes())?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Print given file of the watch like `hexdump -C`.
+pub async fn hexdump(bangle: &Bangle, remote: &str) -> Result<()> {
+    let content = bangle.read_file(remote).await?;
+    let mut stdout = std::io::stdout().lock();
+    for (i, line) in content.chunks(16).enumerate() {
+        let mut hex = String::new();
+        for (j, byte) in line.iter().enumerate() {
+            hex.push_str(if j == 8 { "  " } else { " " });
+            hex.push_str(&format!("{byte:02x}"));
+        }
+        let ascii: String = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        writeln!(stdout, "{:08x} {hex:<49}  |{ascii}|", i * 16)?;
+    }
+    writeln!(stdout, "{:08x}", content.len())?;
+    Ok(())
+}
+
+/// Edit given file of the watch with `$EDITOR`, uploading it back if it changed.
+pub async fn edit(bangle: &Bangle, remote: &str) -> Result<()> {
+    let kind = bangle.file_kind(remote).await?;
+    let content = bangle.read_file(remote).await?;
+    // a private directory, removed when dropped
+    let directory = tempfile::Builder::new().prefix("banglecomm-").tempdir()?;
+    // keep the name so the editor knows the file type
+    let local = directory.path().join(remote.replace('/', "_"));
+    tokio::fs::write(&local, &content).await?;
+    let editor = std::env::var("EDITOR")
+        .or_else(|_| std::env::var("VISUAL"))
+        .unwrap_or("vi".to_string());
+    let mut words = editor.split_whitespace();
+    let status = tokio::process::Command::new(words.next().unwrap_or("vi"))
+        .args(words)
+        .arg(&local)
+        .status()
+        .await;
+    let edited = tokio::fs::read(&local).await;
+    directory.close()?;
+    anyhow::ensure!(
+        status?.success(),
+        "{editor} failed, {remote} left unchanged"
+    );
+    let edited = edited?;
+    if edited == content {
+        println!("{remote} unchanged");
+        return Ok(());
+    }
+    match kind {
+        FileKind::Plain => bangle.write_file(remote, &edited).await?,
+        FileKind::StorageFile => bangle.write_storage_file(remote, &edited).await?,
+    }
+    bangle.check_file(remote, &edited).await?;
+    println!("{remote} updated");
+    Ok(())
+}
+
+pub async fn get(
+    bangle: &Bangle,
+    remote: &str,
+    local: Option<String>,
+    dry_run: bool,
+) -> Result<()> {
+    let files = remote_files(bangle, remote).await?;
+    let local = local.as_deref();
+    for_each(&files, "get", dry_run, |file| async move {
+        let destination = destination(remote, local, file)?;
+        let content = bangle.read_file(file).await?;
+        utils::save_file(&destination, &content).await
+    })
+    .await
+}
+
+/// Where `get` saves given file of the watch, `remote` being the name or pattern asked for.
+fn destination(remote: &str, local: Option<&str>, file: &str) -> Result<String> {
+    if let Some(local) = local.filter(|local| !is_pattern(remote) && !Path::new(local).is_dir()) {
+        return Ok(local.to_string());
+    }
+    anyhow::ensure!(is_safe_name(file), "unsafe name {file}");
+    // several files go in a directory, like cp keep the name when given one
+    Ok(path(local.unwrap_or("."), file))
+}
+
+/// Can given name of the watch be used as a local file name without leaving the directory ?
+fn is_safe_name(name: &str) -> bool {
+    !name.contains('/') && !name.contains("..")
+}
+
+pub async fn put(
+    bangle: &Bangle,
+    locals: &[String],
+    remote: Option<String>,
+    storage_file: bool,
+    dry_run: bool,
+) -> Result<()> {
+    // `put <local> <remote>` uploads a single file under another name
+    let (locals, remote) = match (locals, remote) {
+        ([local, name], None) if !is_pattern(local) && !is_pattern(name) => {
+            (std::slice::from_ref(local), Some(name.clone()))
+        }
+        (locals, remote) => (locals, remote),
+    };
+    anyhow::ensure!(
+        remote.is_none() || matches!(locals, [local] if !is_pattern(local)),
+        "a remote name can only be given for a single file"
+    );
+    let mut files = Vec::new();
+    for local in locals {
+        files.extend(local_files(local)?);
+    }
+    files.sort();
+    files.dedup();
+    let remote = &remote;
+    for_each(&files, "put", dry_run, |file| async move {
+        upload(bangle, file, remote.as_deref(), storage_file).await
+    })
+    .await
+}
+
+async fn upload(
+    bangle: &Bangle,
+    local: &str,
+    remote: Option<&str>,
+    storage_file: bool,
+) -> Result<()> {
+    // only keep the name of local paths
+    let remote = match remote {
+        Some(remote) => remote,
+        None if local == utils::STDIO => anyhow::bail!("a remote name is needed for stdin"),
+        None => Path::new(local)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow::anyhow!("invalid file name {local}"))?,
+    };
+    let file_content = utils::read_file(local).await?;
+    if storage_file {
+        bangle.write_storage_file(remote, &file_content).await
+    } else {
+        bangle.write_file(remote, &file_content).await
+    }
+}
+
+pub async fn rm(bangle: &Bangle, patterns: &[String], dry_run: bool) -> Result<()> {
+    let mut files = Vec::new();
+    for pattern in patterns {
+        files.extend(remote_files(bangle, pattern).await?);
+    }
+    files.sort();
+    files.dedup();
+    for_each(&files, "rm", dry_run, |file| bangle.erase(file)).await
+}
+
+/// Files sync leaves alone unless explicitly included : espruino's boot code and settings,
+/// or local hidden files.
+fn is_system_file(name: &str) -> bool {
+    name.starts_with('.') || name == "setting.json"
+}
+
+/// Why given local file can't go to the watch, if it can't.
+fn unsyncable(name: &str, content: &[u8]) -> Option<String> {
+    if content.is_empty() {
+        Some("empty file".to_string())
+    } else if name.len() > MAX_FILENAME_LEN {
+        Some(format!("name longer than {MAX_FILENAME_LEN} chars"))
+    } else {
+        None
+    }
+}
+
+/// Make the watch's plain files identical to the files of given directory,
+/// or the other way around when pulling. Only changed files are transferred.
+pub async fn sync(
+    bangle: &Bangle,
+    directory: &str,
+    pull: bool,
+    delete: bool,
+    selection: &Selection,
+    dry_run: bool,
+) -> Result<()> {
+    let synced =
+        |name: &str| selection.contains(name) && (!is_system_file(name) || selection.lists(name));
+    let remote: BTreeMap<String, FileInfo> = bangle
+        .list_file_infos(true)
+        .await?
+        .into_iter()
+        .filter(|info| info.kind == FileKind::Plain && synced(&info.name))
+        .map(|info| (info.name.clone(), info))
+        .collect();
+    let mut local = BTreeMap::new();
+    let mut entries = tokio::fs::read_dir(directory).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_file() {
+            if let Some(name) = entry.file_name().to_str().filter(|name| synced(name)) {
+                local.insert(
+                    name.to_string(),
+                    utils::read_file(&path(directory, name)).await?,
+                );
+            }
+        }
+    }
+    let unchanged = |name: &str, content: &[u8]| {
+        remote.get(name).is_some_and(|info| {
+            info.size == content.len() && info.crc == Some(crc32fast::hash(content))
+        })
+    };
+    let (mut transferred, mut deleted, mut kept, mut failed) = (0, 0, 0, 0);
+    // go on with other files when one fails
+    let mut report = |action: &str, name: &str, result: Result<()>| match result {
+        Ok(()) => println!("{action} {name}"),
+        Err(e) => {
+            failed += 1;
+            println!("{action} {name}: failed: {e}");
+        }
+    };
+    if pull {
+        for name in remote.keys() {
+            if !is_safe_name(name) {
+                println!("skipping {name}: unsafe name");
+                continue;
+            }
+            if local
+                .get(name)
+                .is_some_and(|content| unchanged(name, content))
+            {
+                kept += 1;
+                continue;
+            }
+            let result = if dry_run {
+                Ok(())
+            } else {
+                match bangle.read_file(name).await {
+                    Ok(content) => utils::save_file(&path(directory, name), &content).await,
+                    Err(e) => Err(e),
+                }
+            };
+            transferred += result.is_ok() as usize;
+            report("get", name, result);
+        }
+        for name in local
+            .keys()
+            .filter(|name| delete && !remote.contains_key(*name))
+        {
+            let local_path = path(directory, name);
+            let result = if dry_run {
+                Ok(())
+            } else {
+                tokio::fs::remove_file(&local_path)
+                    .await
+                    .map_err(Into::into)
+            };
+            deleted += result.is_ok() as usize;
+            report("rm", &local_path, result);
+        }
+    } else {
+        for (name, content) in &local {
+            if unchanged(name, content) {
+                kept += 1;
+                continue;
+            }
+            if let Some(reason) = unsyncable(name, content) {
+                println!("skipping {name}: {reason}");
+                continue;
+            }
+            let result = if dry_run {
+                Ok(())
+            } else {
+                bangle.write_file(name, content).await
+            };
+            transferred += result.is_ok() as usize;
+            report("put", name, result);
+        }
+        for name in remote
+            .keys()
+            .filter(|name| delete && !local.contains_key(*name))
+        {
+            let result = if dry_run {
+                Ok(())
+            } else {
+                bangle.erase(name).await
+            };
+            deleted += result.is_ok() as usize;
+            report("rm", name, result);
+        }
+    }
+    println!("{transferred} transferred, {deleted} deleted, {kept} unchanged, {failed} failed");
+    anyhow::ensure!(failed == 0, "{failed} files failed");
+    Ok(())
+}
+
+fn path(directory: &str, name: &str) -> String {
+    Path::new(directory)
+        .join(name)
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use banglecomm::transport::fake::FakeWatch;
+
+    fn connect(watch: &FakeWatch) -> Bangle {
+        Bangle::new(Box::new(watch.clone()))
+    }
+
+    #[tokio::test]
+    async fn patterns_match_remote_files() {
+        let watch = FakeWatch::new();
+        for name in ["health-2.raw", "health-1.raw", "health.json", "app.js"] {
+            watch.set_file(name, b"1");
+        }
+        watch.set_storage_file("health-3.raw", b"1");
+        let bangle = connect(&watch);
+        assert_eq!(
+            remote_files(&bangle, "health-*.raw").await.unwrap(),
+            ["health-1.raw", "health-2.raw", "health-3.raw"]
+        );
+        assert_eq!(
+            remote_files(&bangle, "health.json").await.unwrap(),
+            ["health.json"]
+        );
+        assert!(remote_files(&bangle, "*.txt").await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn patterns_match_local_files() {
+        let directory = tempfile::tempdir().unwrap();
+        for name in ["b.js", "a.js", "a.json"] {
+            std::fs::write(directory.path().join(name), b"1").unwrap();
+        }
+        let directory = directory.path().to_str().unwrap();
+        assert_eq!(
+            local_files(&path(directory, "*.js")).unwrap(),
+            [path(directory, "a.js"), path(directory, "b.js")]
+        );
+        assert_eq!(local_files("a.js").unwrap(), ["a.js"]);
+    }
+
+    #[test]
+    fn get_chooses_the_destination() {
+        let directory = tempfile::tempdir().unwrap();
+        let directory = directory.path().to_str().unwrap();
+        let destination = |remote, local, file| destination(remote, local, file).unwrap();
+        assert_eq!(destination("app.js", None, "app.js"), path(".", "app.js"));
+        assert_eq!(destination("app.js", Some("copy.js"), "app.js"), "copy.js");
+        assert_eq!(
+            destination("app.js", Some(directory), "app.js"),
+            path(directory, "app.js")
+        );
+        assert_eq!(destination("*.js", None, "app.js"), path(".", "app.js"));
+        assert_eq!(
+            destination("*.js", Some("saved"), "app.js"),
+            path("saved", "app.js")
+        );
+    }
+
+    #[test]
+    fn get_keeps_files_in_their_directory() {
+        for file in ["../app.js", "/etc/app.js", "a/b.js"] {
+            assert!(super::destination("*", Some("saved"), file).is_err());
+            assert!(super::destination("*", None, file).is_err());
+        }
+        // an explicit local name is fine
+        assert_eq!(
+            super::destination("../app.js", Some("app.js"), "../app.js").unwrap(),
+            "app.js"
+        );
+    }
+
+    #[tokio::test]
+    async fn put_takes_a_remote_name_or_several_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let local = |name: &str| {
+            let local = path(directory.path().to_str().unwrap(), name);
+            std::fs::write(&local, name).unwrap();
+            local
+        };
+        let (a, b, c) = (local("a.js"), local("b.js"), local("c.js"));
+        let watch = FakeWatch::new();
+        let bangle = connect(&watch);
+        put(
+            &bangle,
+            &[a.clone(), "app.js".to_string()],
+            None,
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(watch.files(), ["app.js"]);
+        assert_eq!(watch.file("app.js").unwrap(), b"a.js");
+        put(&bangle, &[a, b, c], None, false, false).await.unwrap();
+        assert_eq!(watch.files(), ["a.js", "app.js", "b.js", "c.js"]);
+        let all = path(directory.path().to_str().unwrap(), "*.js");
+        assert!(
+            put(&bangle, &[all], Some("app.js".to_string()), false, false)
+                .await
+                .is_err()
+        );
+    }
+
+    /// A directory holding given files.
+    fn directory(files: &[(&str, &[u8])]) -> tempfile::TempDir {
+        let directory = tempfile::tempdir().unwrap();
+        for (name, content) in files {
+            std::fs::write(directory.path().join(name), content).unwrap();
+        }
+        directory
+    }
+
+    fn local_names(directory: &tempfile::TempDir) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn all() -> Selection {
+        Selection::new(&[], &[]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn sync_only_uploads_changed_files() {
+        let watch = FakeWatch::new();
+        watch.set_file("same.js", b"same");
+        watch.set_file("changed.js", b"old");
+        watch.set_file("extra.js", b"extra");
+        watch.set_file("setting.json", b"{}");
+        let local = directory(&[
+            ("same.js", b"same"),
+            ("changed.js", b"new"),
+            ("new.js", b"new"),
+            ("setting.json", b"{\"beep\":1}"),
+            (".hidden", b"hidden"),
+        ]);
+        let bangle = connect(&watch);
+        let directory = local.path().to_str().unwrap();
+        sync(&bangle, directory, false, false, &all(), false)
+            .await
+            .unwrap();
+        assert_eq!(watch.file("changed.js").unwrap(), b"new");
+        assert_eq!(watch.file("new.js").unwrap(), b"new");
+        // system files are only synced when explicitly included
+        assert_eq!(watch.file("setting.json").unwrap(), b"{}");
+        assert_eq!(watch.file(".hidden"), None);
+        // only the changed file was replaced
+        assert_eq!(bangle.storage_stats().await.unwrap().trash_count, 1);
+
+        sync(&bangle, directory, false, true, &all(), false)
+            .await
+            .unwrap();
+        assert_eq!(
+            watch.files(),
+            ["changed.js", "new.js", "same.js", "setting.json"]
+        );
+        assert_eq!(bangle.storage_stats().await.unwrap().trash_count, 2);
+
+        let settings = Selection::new(&["setting.json".to_string()], &[]).unwrap();
+        sync(&bangle, directory, false, false, &settings, false)
+            .await
+            .unwrap();
+        assert_eq!(watch.file("setting.json").unwrap(), b"{\"beep\":1}");
+    }
+
+    #[tokio::test]
+    async fn sync_pulls_changed_files() {
+        let watch = FakeWatch::new();
+        watch.set_file("same.js", b"same");
+        watch.set_file("changed.js", b"new");
+        watch.set_file("new.js", b"new");
+        watch.set_file(".boot0", b"boot");
+        // absolute names replace the directory they are joined to
+        let outside = tempfile::tempdir().unwrap();
+        let escaped = path(outside.path().to_str().unwrap(), "escaped.js");
+        watch.set_file(&escaped, b"evil");
+        let local = directory(&[
+            ("same.js", b"same"),
+            ("changed.js", b"old"),
+            ("extra.js", b"extra"),
+            (".hidden", b"hidden"),
+        ]);
+        let bangle = connect(&watch);
+        let directory = local.path().to_str().unwrap();
+        sync(&bangle, directory, true, false, &all(), false)
+            .await
+            .unwrap();
+        let content = |name| std::fs::read(local.path().join(name)).unwrap();
+        assert_eq!(content("changed.js"), b"new");
+        assert_eq!(content("new.js"), b"new");
+        assert_eq!(
+            local_names(&local),
+            [".hidden", "changed.js", "extra.js", "new.js", "same.js"]
+        );
+        // names of the watch can't take us out of the directory
+        assert!(!Path::new(&escaped).exists());
+
+        sync(&bangle, directory, true, true, &all(), false)
+            .await
+            .unwrap();
+        assert_eq!(
+            local_names(&local),
+            [".hidden", "changed.js", "new.js", "same.js"]
+        );
+    }
+
+    #[tokio::test]
+    async fn sync_dry_run_changes_nothing() {
+        let watch = FakeWatch::new();
+        watch.set_file("changed.js", b"old");
+        watch.set_file("extra.js", b"extra");
+        let local = directory(&[("changed.js", b"new"), ("new.js", b"new")]);
+        let bangle = connect(&watch);
+        let directory = local.path().to_str().unwrap();
+        for pull in [false, true] {
+            sync(&bangle, directory, pull, true, &all(), true)
+                .await
+                .unwrap();
+            assert_eq!(watch.files(), ["changed.js", "extra.js"]);
+            assert_eq!(watch.file("changed.js").unwrap(), b"old");
+            assert_eq!(local_names(&local), ["changed.js", "new.js"]);
+            assert_eq!(
+                std::fs::read(local.path().join("changed.js")).unwrap(),
+                b"new"
+            );
+        }
+    }
+}
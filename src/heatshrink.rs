@@ -0,0 +1,116 @@
+//! Heatshrink (LZSS) compression with the parameters used by espruino's
+//! `require("heatshrink")` : a 256 bytes window and 16 bytes lookahead.
+//!
+//! The stream is a sequence of bit-packed items, most significant bit first :
+//! `1` followed by a literal byte, or `0` followed by a back-reference
+//! (offset - 1 on 8 bits, length - 1 on 4 bits).
+
+const WINDOW_BITS: u32 = 8;
+const LOOKAHEAD_BITS: u32 = 4;
+const WINDOW: usize = 1 << WINDOW_BITS;
+const LOOKAHEAD: usize = 1 << LOOKAHEAD_BITS;
+/// Shorter back-references take more bits than literals.
+const MIN_MATCH: usize = 2;
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut output = BitWriter::default();
+    let mut position = 0;
+    while position < data.len() {
+        let longest = (position.saturating_sub(WINDOW)..position)
+            .map(|start| {
+                let length = data[start..]
+                    .iter()
+                    .zip(&data[position..])
+                    .take(LOOKAHEAD)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                (length, position - start)
+            })
+            // prefer the closest match
+            .max_by_key(|&(length, offset)| (length, std::cmp::Reverse(offset)));
+        match longest {
+            Some((length, offset)) if length >= MIN_MATCH => {
+                output.push(0, 1);
+                output.push(offset as u32 - 1, WINDOW_BITS);
+                output.push(length as u32 - 1, LOOKAHEAD_BITS);
+                position += length;
+            }
+            _ => {
+                output.push(1, 1);
+                output.push(data[position] as u32, 8);
+                position += 1;
+            }
+        }
+    }
+    output.finish()
+}
+
+pub fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut input = BitReader { data, position: 0 };
+    let mut output: Vec<u8> = Vec::new();
+    // trailing padding bits are too short to make an item
+    while let Some(tag) = input.pop(1) {
+        if tag == 1 {
+            let Some(byte) = input.pop(8) else { break };
+            output.push(byte as u8);
+        } else {
+            let (Some(offset), Some(length)) = (input.pop(WINDOW_BITS), input.pop(LOOKAHEAD_BITS))
+            else {
+                break;
+            };
+            let start = output.len().saturating_sub(offset as usize + 1);
+            // the reference can overlap what it produces
+            for i in start..start + length as usize + 1 {
+                output.push(output.get(i).copied().unwrap_or(0));
+            }
+        }
+    }
+    output
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits already used in the last byte.
+    used: u32,
+}
+
+impl BitWriter {
+    fn push(&mut self, value: u32, bits: u32) {
+        for bit in (0..bits).rev() {
+            if self.bytes.is_empty() || self.used == 8 {
+                self.bytes.push(0);
+                self.used = 0;
+            }
+            if value >> bit & 1 == 1 {
+                *self.bytes.last_mut().unwrap() |= 0x80 >> self.used;
+            }
+            self.used += 1;
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// In bits.
+    position: usize,
+}
+
+impl BitReader<'_> {
+    fn pop(&mut self, bits: u32) -> Option<u32> {
+        if self.position + bits as usize > self.data.len() * 8 {
+            return None;
+        }
+        let mut value = 0;
+        for _ in 0..bits {
+            let byte = self.data[self.position / 8];
+            value = value << 1 | (byte >> (7 - self.position % 8) & 1) as u32;
+            self.position += 1;
+        }
+        Some(value)
+    }
+}
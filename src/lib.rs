@@ -0,0 +1,10 @@
+//! Talk to a banglejs watch (or any espruino device).
+pub mod archive;
+pub mod bangle;
+pub mod heatshrink;
+pub mod network;
+mod pairing;
+pub mod transport;
+pub mod utils;
+
+pub use bangle::{Bangle, CalendarEvent, FileInfo, FileKind, Progress, StorageStats};
@@ -1,56 +1,84 @@
+use banglecomm::transport::{
+    ble::{self, BleTransport},
+    process::ProcessTransport,
+    serial, tcp, Transport,
+};
+use banglecomm::{archive, utils, Bangle, CalendarEvent};
 use chrono::{NaiveDateTime, Utc};
 use clap::Parser;
 use directories_next::ProjectDirs;
-use itertools::Itertools;
-use std::fmt::Write;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
 
 use rustyline::error::ReadlineError;
 use rustyline::DefaultEditor;
 
-mod network;
-use network::Communicator;
-mod pairing;
-
 mod cli;
+mod config;
+mod files;
+mod progress;
 use cli::Command;
 
-pub mod utils;
-
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = cli::Cli::parse();
 
-    let mut history_file = ProjectDirs::from("", "", "BangleComm")
+    let data_dir = ProjectDirs::from("", "", "BangleComm")
         .map(|proj_dirs| proj_dirs.data_local_dir().to_path_buf())
         .unwrap_or_else(|| Path::new(".").to_path_buf());
-    tokio::fs::create_dir_all(&history_file).await?;
-    history_file.push("history.txt");
+    tokio::fs::create_dir_all(&data_dir).await?;
+    let history_file = data_dir.join("history.txt");
+
+    // no need to connect to anything for this one
+    if let Some(Command::Scan { duration, json }) = cli.commands {
+        return scan(duration, json).await;
+    }
 
-    let comms = Arc::new(Communicator::new().await?);
+    let transport: Box<dyn Transport> = if let Some(device) = &cli.serial {
+        Box::new(serial::open(device, cli.baud)?)
+    } else if let Some(address) = &cli.tcp {
+        Box::new(tcp::connect(address).await?)
+    } else if let Some(command_line) = &cli.spawn {
+        Box::new(ProcessTransport::spawn(command_line)?)
+    } else {
+        Box::new(connect_ble(&cli, &data_dir.join("devices.json")).await?)
+    };
+    let bangle = Bangle::new(transport)
+        .with_timeout(Duration::from_secs(cli.timeout))
+        .with_verification(!cli.no_verify)
+        .with_progress(Arc::new(progress::TransferDisplay::new()));
 
-    // spawn the receiver
-    let recv_comms = comms.clone();
-    tokio::task::spawn(async {
-        network::receive_messages(recv_comms)
-            .await
-            .expect("receiving messages failed")
+    // display everything the watch says, away from data sent to stdout
+    let to_stderr = cli
+        .commands
+        .as_ref()
+        .is_some_and(|command| command.writes_to_stdout());
+    let mut console = bangle.console();
+    tokio::task::spawn(async move {
+        loop {
+            match console.recv().await {
+                Ok(line) if to_stderr => eprintln!("{line}"),
+                Ok(line) => println!("{line}"),
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => (),
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
     });
 
     if let Some(command) = cli.commands {
         let stay_alive = matches!(command, Command::App { .. });
-        execute_cli_command(&comms, command).await?;
+        execute_cli_command(&bangle, command).await?;
         if stay_alive {
             loop {
-                tokio::time::sleep(std::time::Duration::new(5, 0)).await;
+                tokio::time::sleep(Duration::new(5, 0)).await;
             }
         }
     } else {
         // sync the clock
-        sync_clock(&comms).await?;
+        sync_clock(&bangle).await?;
 
         // start the command line interface
         let mut rl = DefaultEditor::new()?;
@@ -66,11 +94,21 @@ async fn main() -> Result<()> {
                         break;
                     }
                     match line.parse::<Command>() {
-                        Err(_) => println!("we cannot parse command : {} ; available commands are 'get' 'put' 'ls' 'rm' 'run'", line),
+                        Err(_) => println!("we cannot parse command : {} ; available commands are 'get' 'put' 'ls' 'rm' 'run' 'app' 'scan' 'sync' 'backup' 'restore' 'df' 'compact' 'cat' 'head' 'hexdump' 'edit' 'mv' 'cp'", line),
 
-                        Ok(command) => if let Err(e) = execute_cli_command(&comms, command).await {
-                            eprintln!("failed: {}", e);
-                        },
+                        Ok(command) => {
+                            let result = tokio::select! {
+                                result = execute_cli_command(&bangle, command) => result,
+                                // only cancel current command, not the whole program
+                                _ = tokio::signal::ctrl_c() => {
+                                    println!("cancelled");
+                                    bangle.interrupt().await
+                                }
+                            };
+                            if let Err(e) = result {
+                                eprintln!("failed: {}", e);
+                            }
+                        }
                     }
                 }
                 Err(ReadlineError::Interrupted) => {
@@ -90,94 +128,52 @@ async fn main() -> Result<()> {
         rl.save_history(&history_file)?; // TODO: how to async ?
     }
     if !cli.keep_connected {
-        comms.disconnect().await?;
+        bangle.disconnect().await?;
     }
 
     Ok(())
 }
 
-async fn sync_clock(comms: &Communicator) -> Result<()> {
-    // setTime((new Date("Tue, 19 Feb 2019 10:57")).getTime()/1000)
-    let now = time::OffsetDateTime::now_utc();
-    let now_in_secs = now.unix_timestamp();
-    *comms.command.lock().await = Some(Command::SyncClock);
-    let msg = format!("\x10setTime({});", now_in_secs);
-    write(comms, &msg).await
-}
-
-async fn download(comms: &Communicator, filename: String) -> Result<()> {
-    let msg = format!(
-        "\x10let ab = require(\"Storage\").readArrayBuffer(\"{}\"); \
-let buff = Uint8Array(ab, 0, ab.length) ;\
-buff.forEach((c, i) => console.log('\\x10', c));",
-        filename
-    );
-    *comms.command.lock().await = Some(Command::Get { filename });
-    comms.send_message(&msg).await?;
-    Ok(())
+/// Connect to the watch chosen on the command line (or the default one) and remember it.
+async fn connect_ble(cli: &cli::Cli, devices_file: &Path) -> Result<BleTransport> {
+    let mut devices = config::Devices::load(devices_file).await?;
+    let query = devices.query(cli.device.as_deref());
+    if let Some(query) = &query {
+        eprintln!("looking for {query}");
+    }
+    let transport = BleTransport::new(query.as_deref()).await?;
+    if cli.device.is_some() || cli.alias.is_some() {
+        let address = transport.address();
+        if let Some(alias) = &cli.alias {
+            devices.aliases.insert(alias.clone(), address.clone());
+        }
+        devices.default = Some(address);
+        devices.save(devices_file).await?;
+    }
+    Ok(transport)
 }
 
-async fn upload(comms: &Communicator, filename: String) -> Result<()> {
-    if filename.len() > 28 {
-        eprintln!("this filename is too large (max 28 chars)");
-        return Ok(());
-    }
-    let file_content = utils::read_file(&filename).await?;
-    let mut chunks = file_content
-        .chunks(1024)
-        .enumerate()
-        .map(|(i, chunk)| (i, chunk.iter().map(|d| d.to_string()).join(",")));
-    let file_size = file_content.len();
-    let first_chunk = chunks.next().ok_or_else(|| anyhow::anyhow!("empty file"))?;
-    let mut msg = format!(
-        "\x10require(\"Storage\").write(\"{}\", [{}], 0, {});",
-        filename, first_chunk.1, file_size
-    );
-    msg.extend(chunks.map(|(index, chunk_msg)| {
-        format!(
-            "require(\"Storage\").write(\"{}\", [{}], {});",
-            filename,
-            chunk_msg,
-            index * 1024
-        )
-    }));
-    write(comms, &msg).await
+async fn sync_clock(bangle: &Bangle) -> Result<()> {
+    let now = time::OffsetDateTime::now_utc();
+    bangle.set_time(now.unix_timestamp()).await
 }
 
-async fn app(comms: &Communicator, filename: String) -> Result<()> {
+async fn app(bangle: &Bangle, filename: String) -> Result<()> {
     let uglified = tokio::process::Command::new("uglifyjs")
         .arg(&filename)
         .output()
         .await?;
     anyhow::ensure!(uglified.status.success(), "uglifyjs failed");
-    let escaped_msg: String = String::from_utf8(uglified.stdout)
-        .unwrap()
-        .split_terminator('\n')
-        .fold(String::new(), |mut s, line| {
-            write!(&mut s, "\x10{}", line).ok();
-            s
-        });
-    *comms.command.lock().await = Some(Command::App { filename });
-    comms.send_message(&escaped_msg).await?;
-    Ok(())
+    bangle.run(std::str::from_utf8(&uglified.stdout)?).await
 }
 
-async fn run(comms: &Communicator, filename: String) -> Result<()> {
+async fn run(bangle: &Bangle, filename: String) -> Result<()> {
     let file_content = utils::read_file(&filename).await?;
-    let msg = std::str::from_utf8(&file_content)?;
-    let escaped_msg: String = msg
-        .split_terminator('\n')
-        .fold(String::new(), |mut s, line| {
-            write!(&mut s, "\x10{}", line).ok();
-            s
-        });
-    *comms.command.lock().await = Some(Command::Run { filename });
-    comms.send_message(&escaped_msg).await?;
-    Ok(())
+    bangle.run(std::str::from_utf8(&file_content)?).await
 }
 
-// parse all events later than now and return summary, optional location and timestamp
-async fn parse_ical_events(filename: &str) -> Result<Vec<(String, Option<String>, i64)>> {
+// parse all events later than now
+async fn parse_ical_events(filename: &str) -> Result<Vec<CalendarEvent>> {
     let file_content = utils::read_file(filename).await?;
     let ical = ical::parser::ical::IcalParser::new(file_content.as_slice())
         .next()
@@ -204,7 +200,11 @@ async fn parse_ical_events(filename: &str) -> Result<Vec<(String, Option<String>
                     let summary = summary
                         .cloned()
                         .unwrap_or_else(|| "unknown event".to_string());
-                    events.push((summary, location.cloned(), date.timestamp()));
+                    events.push(CalendarEvent {
+                        title: summary,
+                        description: location.cloned(),
+                        timestamp: date.timestamp(),
+                    });
                 }
             }
         }
@@ -213,68 +213,173 @@ async fn parse_ical_events(filename: &str) -> Result<Vec<(String, Option<String>
 }
 
 // NOTE: this will erase all existing calendar events
-async fn sync_calendar(comms: &Communicator, filename: String) -> Result<()> {
+async fn sync_calendar(bangle: &Bangle, filename: String) -> Result<()> {
     let events = parse_ical_events(&filename).await?;
-    *comms.command.lock().await = Some(Command::SyncCalendar {
-        ical_filename: filename,
-    });
-    let mut msg = events.iter().fold(
-        "\x10let e=[];".to_string(),
-        |mut s, (summary, location, time)| {
-            if let Some(location) = location {
-                write!(
-                    &mut s,
-                    "e.push({{title:\"{}\", description: \"{}\", timestamp: {time}}});",
-                    summary, location
-                )
-                .ok();
-            } else {
-                write!(
-                    &mut s,
-                    "e.push({{title:\"{}\", timestamp: {time}}});",
-                    summary
-                )
-                .ok();
-            };
-            s
-        },
+    bangle.set_calendar(&events).await
+}
+
+async fn df(bangle: &Bangle, json: bool) -> Result<()> {
+    let stats = bangle.storage_stats().await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+    let size = |bytes: usize| progress::human_size(bytes as f64);
+    println!("total {:>10}", size(stats.total_bytes));
+    println!(
+        "files {:>10} ({} files)",
+        size(stats.file_bytes),
+        stats.file_count
+    );
+    println!(
+        "trash {:>10} ({} files)",
+        size(stats.trash_bytes),
+        stats.trash_count
     );
-    msg.push_str("require(\"Storage\").writeJSON(\"android.calendar.json\", e);");
-    comms.send_message(&msg).await?;
+    println!("free  {:>10}", size(stats.free_bytes));
     Ok(())
 }
 
-async fn write(comms: &Communicator, code: &str) -> Result<()> {
-    *comms.command.lock().await = None;
-    comms.send_message(code).await?;
+async fn compact(bangle: &Bangle) -> Result<()> {
+    let before = bangle.storage_stats().await?.free_bytes;
+    let after = bangle.compact().await?.free_bytes;
+    println!(
+        "{} reclaimed, {} free",
+        progress::human_size(after.saturating_sub(before) as f64),
+        progress::human_size(after as f64)
+    );
     Ok(())
 }
 
-async fn rm(comms: &Communicator, filename: String) -> Result<()> {
-    let msg = format!("\x10require(\"Storage\").erase(\"{}\");", filename);
-    write(comms, &msg).await
+async fn backup(bangle: &Bangle, archive: &str, selection: &archive::Selection) -> Result<()> {
+    let saved = archive::backup(bangle, archive, selection).await?;
+    // stdout may be the archive
+    for info in &saved {
+        eprintln!("{} ({} bytes)", info.name, info.size);
+    }
+    eprintln!("{} files saved in {archive}", saved.len());
+    Ok(())
 }
 
-async fn ls(comms: &Communicator) -> Result<()> {
-    write(
-        comms,
-        "\x10let l = require(\"Storage\").list(); l.forEach((f, i) => console.log(f));",
-    )
-    .await
+async fn restore(bangle: &Bangle, archive: &str, selection: &archive::Selection) -> Result<()> {
+    let restored = archive::restore(bangle, archive, selection).await?;
+    for info in &restored.files {
+        eprintln!("{} ({} bytes)", info.name, info.size);
+    }
+    for name in &restored.skipped {
+        eprintln!("skipping {name}: empty file");
+    }
+    for (name, e) in &restored.failed {
+        eprintln!("{name}: failed: {e}");
+    }
+    let failed = restored.failed.len();
+    eprintln!(
+        "{} files restored from {archive}, {failed} failed",
+        restored.files.len()
+    );
+    anyhow::ensure!(failed == 0, "{failed} files failed");
+    Ok(())
 }
 
-async fn execute_cli_command(comms: &Communicator, command: Command) -> Result<()> {
+async fn scan(duration: u64, json: bool) -> Result<()> {
+    let devices = ble::scan(Duration::from_secs(duration)).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&devices)?);
+        return Ok(());
+    }
+    for device in devices {
+        let rssi = device
+            .rssi
+            .map(|rssi| format!("{rssi} dBm"))
+            .unwrap_or_default();
+        let status = match (device.connected, device.paired) {
+            (true, _) => "connected",
+            (false, true) => "paired",
+            (false, false) => "",
+        };
+        println!(
+            "{:<20} {:<17} {:>8} {:<9} {}",
+            device.name,
+            device.address,
+            rssi,
+            status,
+            device.services.join(",")
+        );
+    }
+    Ok(())
+}
+
+async fn execute_cli_command(bangle: &Bangle, command: Command) -> Result<()> {
     match command {
-        Command::App { filename: f } => app(comms, f).await?,
+        Command::App { filename: f } => app(bangle, f).await?,
         Command::Disconnect => (), // do nothing, we'll disconnect at the end
-        Command::SyncClock => sync_clock(comms).await?,
-        Command::Get { filename: f } => download(comms, f).await?,
-        Command::Put { filename: f } => upload(comms, f).await?,
-        Command::SyncCalendar { ical_filename: f } => sync_calendar(comms, f).await?,
-        Command::Ls => ls(comms).await?,
-        Command::Rm { filename: f } => rm(comms, f).await?,
-        Command::Run { filename: f } => run(comms, f).await?,
-        Command::Write { code: c } => write(comms, &c).await?,
+        Command::SyncClock => sync_clock(bangle).await?,
+        Command::Get {
+            remote,
+            local,
+            dry_run,
+        } => files::get(bangle, &remote, local, dry_run).await?,
+        Command::Put {
+            local,
+            remote,
+            storage_file,
+            dry_run,
+        } => files::put(bangle, &local, remote, storage_file, dry_run).await?,
+        Command::SyncCalendar { ical_filename: f } => sync_calendar(bangle, f).await?,
+        Command::Ls {
+            filter,
+            long,
+            group,
+            sort,
+            json,
+        } => files::ls(bangle, filter.as_deref(), long, group, sort, json).await?,
+        Command::Sync {
+            directory,
+            pull,
+            delete,
+            include,
+            exclude,
+            dry_run,
+        } => {
+            let selection = archive::Selection::new(&include, &exclude)?;
+            files::sync(bangle, &directory, pull, delete, &selection, dry_run).await?
+        }
+        Command::Backup {
+            archive,
+            include,
+            exclude,
+        } => {
+            backup(
+                bangle,
+                &archive,
+                &archive::Selection::new(&include, &exclude)?,
+            )
+            .await?
+        }
+        Command::Restore {
+            archive,
+            include,
+            exclude,
+        } => {
+            restore(
+                bangle,
+                &archive,
+                &archive::Selection::new(&include, &exclude)?,
+            )
+            .await?
+        }
+        Command::Df { json } => df(bangle, json).await?,
+        Command::Compact => compact(bangle).await?,
+        Command::Cat { remote } => files::cat(bangle, &remote, None).await?,
+        Command::Head { remote, lines } => files::cat(bangle, &remote, Some(lines)).await?,
+        Command::Hexdump { remote } => files::hexdump(bangle, &remote).await?,
+        Command::Edit { remote } => files::edit(bangle, &remote).await?,
+        Command::Mv { from, to, force } => bangle.rename(&from, &to, force).await?,
+        Command::Cp { from, to, force } => bangle.copy(&from, &to, force).await?,
+        Command::Rm { filenames, dry_run } => files::rm(bangle, &filenames, dry_run).await?,
+        Command::Run { filename: f } => run(bangle, f).await?,
+        Command::Write { code: c } => bangle.write(&c).await?,
+        Command::Scan { duration, json } => scan(duration, json).await?,
     }
     Ok(())
 }
@@ -1,81 +1,348 @@
-use super::pairing::StdioPairingAgent;
-use super::Command;
+use crate::transport::Transport;
 use anyhow::Result;
-use bluest::{Adapter, Characteristic, Device, Uuid};
-use futures_util::{AsyncBufReadExt, StreamExt, TryStreamExt};
+use futures_util::{future, AsyncBufReadExt, TryStreamExt};
+use std::collections::BTreeMap;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
+    atomic::{AtomicBool, AtomicU32, Ordering},
     Arc,
 };
-use tokio::sync::{Mutex, Notify};
+use std::time::Duration;
+use tokio::sync::{broadcast, oneshot, Mutex, Notify};
+use tokio::time::Instant;
 
-const NORDIC_UUID: &str = "6e400001-b5a3-f393-e0a9-e50e24dcca9e";
-const NORDIC_UART_TX_UUID: &str = "6e400002-b5a3-f393-e0a9-e50e24dcca9e";
-const NORDIC_UART_RX_UUID: &str = "6e400003-b5a3-f393-e0a9-e50e24dcca9e";
+const END_TOKEN: &str = "8210409291035902";
 
-const END_TOKEN: &str = "\x108210409291035902";
+/// The watch stayed silent for too long.
+#[derive(Debug)]
+pub struct Timeout(pub Duration);
+
+impl std::fmt::Display for Timeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no answer from the watch for {:?}", self.0)
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+/// Javascript exception raised on the watch while running a message.
+#[derive(Debug, Clone)]
+pub struct JsException {
+    /// The "Uncaught ..." line.
+    pub message: String,
+    /// Lines displayed after it (position, code).
+    pub trace: Vec<String>,
+}
+
+impl std::fmt::Display for JsException {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for JsException {}
+
+/// Called with the number of bytes sent or received for a message.
+pub type OnProgress = Arc<dyn Fn(usize) + Send + Sync>;
+
+/// A message sent to the watch and still waiting for its end token.
+struct Pending {
+    /// Data sent back by the message.
+    lines: Vec<String>,
+    /// Exception raised by the message.
+    exception: Option<JsException>,
+    done: oneshot::Sender<Result<Vec<String>, JsException>>,
+    /// Last time we sent something for this message or the watch said anything while running it.
+    last_activity: Instant,
+    progress: Option<OnProgress>,
+}
+
+/// Ordered by id, which is also the order of execution on the watch.
+type PendingMap = std::sync::Mutex<BTreeMap<u32, Pending>>;
+
+/// Forget a message when its requester gives up (or is done).
+struct PendingGuard<'a> {
+    pending: &'a PendingMap,
+    id: u32,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.id);
+    }
+}
 
 pub struct Communicator {
-    adapter: Adapter,
-    rx: Characteristic,
-    tx: Characteristic,
-    bangle: Device,
+    transport: Box<dyn Transport>,
     paused_notifier: Notify,
-    receive_notifier: Notify,
     paused: AtomicBool,
-    pub command: Mutex<Option<Command>>,
+    /// Set once we stop listening to the watch, on purpose or not.
+    closed: AtomicBool,
+    /// Held while a message is being written so that messages don't get mixed.
+    writing: Mutex<()>,
+    next_id: AtomicU32,
+    pending: PendingMap,
+    /// All lines displayed by the watch which are not part of a response.
+    console: broadcast::Sender<String>,
 }
 
 impl Communicator {
-    pub async fn new() -> Result<Self> {
-        // open bluetooth
-        let adapter = Adapter::default()
-            .await
-            .ok_or_else(|| anyhow::anyhow!("Bluetooth adapter not found"))?;
-        adapter.wait_available().await?;
-
-        // find the watch
-        let bangle = find_banglejs(&adapter).await?;
-
-        // get the communication channels from the watch
-        let (tx, rx) = tx_rx(&bangle).await?;
-
-        Ok(Communicator {
-            adapter,
-            rx,
-            tx,
-            bangle,
+    pub fn new(transport: Box<dyn Transport>) -> Self {
+        Communicator {
+            transport,
             paused_notifier: Notify::new(),
-            receive_notifier: Notify::new(),
             paused: AtomicBool::new(false),
-            command: Mutex::new(None),
-        })
+            closed: AtomicBool::new(false),
+            writing: Mutex::new(()),
+            next_id: AtomicU32::new(0),
+            pending: std::sync::Mutex::new(BTreeMap::new()),
+            console: broadcast::channel(1024).0,
+        }
+    }
+
+    /// Subscribe to everything the watch displays outside of responses.
+    pub fn console(&self) -> broadcast::Receiver<String> {
+        self.console.subscribe()
     }
 
     pub async fn disconnect(&self) -> Result<()> {
-        println!("disconnecting");
-        self.adapter.disconnect_device(&self.bangle).await?;
+        // don't try to get the link back
+        self.closed.store(true, Ordering::Relaxed);
+        self.transport.disconnect().await
+    }
+
+    /// Send ctrl-c : stop running code and clear the watch's input line.
+    ///
+    /// To be used after giving up on a message.
+    pub async fn interrupt(&self) -> Result<()> {
+        let _writing = self.writing.lock().await;
+        self.transport.write(b"\x03").await
+    }
+
+    /// Send given code and wait for its execution.
+    ///
+    /// Fail if the watch stays silent for more than `timeout`.
+    pub async fn send_message(&self, msg: &str, timeout: Duration) -> Result<()> {
+        self.exchange(|_| msg.to_string(), timeout, None, None)
+            .await?;
         Ok(())
     }
 
-    pub async fn send_message(&self, msg: &str) -> Result<()> {
-        let msg = format!("{}\n\x10console.log('\\x10{}');\n", msg, END_TOKEN);
-        let max_len = self.tx.max_write_len()?;
-        for chunk in msg.as_bytes().chunks(max_len) {
-            while self.paused.load(Ordering::Relaxed) {
-                self.paused_notifier.notified().await;
+    /// Like `send_message`, reporting the bytes sent.
+    pub async fn send_message_with_progress(
+        &self,
+        msg: &str,
+        timeout: Duration,
+        progress: OnProgress,
+    ) -> Result<()> {
+        self.exchange(|_| msg.to_string(), timeout, Some(progress), None)
+            .await?;
+        Ok(())
+    }
+
+    /// Run given function body on the watch and wait for its execution.
+    ///
+    /// The body sends data back by calling `reply(data)`, we return all of it.
+    /// Fail if the watch stays silent for more than `timeout`.
+    pub async fn request(&self, body: &str, timeout: Duration) -> Result<Vec<String>> {
+        self.exchange(|id| request_code(body, id), timeout, None, None)
+            .await
+    }
+
+    /// Like `request`, reporting the bytes of data received.
+    pub async fn request_with_progress(
+        &self,
+        body: &str,
+        timeout: Duration,
+        progress: OnProgress,
+    ) -> Result<Vec<String>> {
+        self.exchange(|id| request_code(body, id), timeout, None, Some(progress))
+            .await
+    }
+
+    fn touch(&self, id: u32) {
+        if let Some(request) = self.pending.lock().unwrap().get_mut(&id) {
+            request.last_activity = Instant::now();
+        }
+    }
+
+    /// Is given message the one the watch is executing ?
+    fn is_running(&self, id: u32) -> bool {
+        self.pending.lock().unwrap().keys().next() == Some(&id)
+    }
+
+    /// Send a message, reporting the bytes written to `sent` and the data received to `received`.
+    async fn exchange(
+        &self,
+        code: impl FnOnce(u32) -> String,
+        timeout: Duration,
+        sent: Option<OnProgress>,
+        received: Option<OnProgress>,
+    ) -> Result<Vec<String>> {
+        anyhow::ensure!(
+            !self.closed.load(Ordering::Relaxed),
+            "connection to the watch closed"
+        );
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (done, mut response) = oneshot::channel();
+        self.pending.lock().unwrap().insert(
+            id,
+            Pending {
+                lines: Vec::new(),
+                exception: None,
+                done,
+                last_activity: Instant::now(),
+                // received data is reported by the router
+                progress: received,
+            },
+        );
+        let _guard = PendingGuard {
+            pending: &self.pending,
+            id,
+        };
+        let msg = format!("{}\n\x10console.log('\\x10{END_TOKEN}{id}');\n", code(id));
+        let written = async {
+            let _writing = self.writing.lock().await;
+            let max_len = self.transport.max_write_len()?;
+            for chunk in msg.as_bytes().chunks(max_len) {
+                let send_chunk = async {
+                    while self.paused.load(Ordering::Relaxed) {
+                        self.paused_notifier.notified().await;
+                    }
+                    self.transport.write(chunk).await
+                };
+                tokio::time::timeout(timeout, send_chunk)
+                    .await
+                    .map_err(|_| Timeout(timeout))??;
+                self.touch(id);
+                if let Some(sent) = &sent {
+                    sent(chunk.len());
+                }
+                tokio::time::sleep(Duration::from_micros(100)).await;
             }
-            self.tx.write(chunk).await?;
-            tokio::time::sleep(std::time::Duration::from_micros(100)).await;
+            Ok::<_, anyhow::Error>(())
         }
-        self.receive_notifier.notified().await;
-        Ok(())
+        .await;
+        if let Err(e) = written {
+            // don't kill another message
+            if self.is_running(id) {
+                self.interrupt().await.ok();
+            }
+            return Err(e);
+        }
+        loop {
+            let last_activity = match self.pending.lock().unwrap().get(&id) {
+                Some(request) => request.last_activity,
+                // already answered
+                None => Instant::now(),
+            };
+            match tokio::time::timeout_at(last_activity + timeout, &mut response).await {
+                Ok(Ok(lines)) => return Ok(lines?),
+                Ok(Err(_)) => anyhow::bail!("connection to the watch lost"),
+                Err(_) if !self.is_running(id) => {
+                    // waiting for the messages before us is not our silence
+                    self.touch(id);
+                }
+                Err(_) => {
+                    let silent = self
+                        .pending
+                        .lock()
+                        .unwrap()
+                        .get(&id)
+                        .is_some_and(|r| r.last_activity + timeout <= Instant::now());
+                    if silent {
+                        self.interrupt().await.ok();
+                        return Err(Timeout(timeout).into());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Code running given function body, its `reply` sending back data tagged with the request id.
+fn request_code(body: &str, id: u32) -> String {
+    format!("\x10(function(reply){{{body}}})(d => console.log('\\x10{id}:' + d));")
+}
+
+/// Dispatch a line received from the watch.
+fn route(comms: &Communicator, line: String) {
+    let mut pending = comms.pending.lock().unwrap();
+    // whatever the watch says, it is alive and busy with the running message
+    if let Some(running) = pending.values_mut().next() {
+        running.last_activity = Instant::now();
+    }
+    let Some(tagged) = line.strip_prefix('\x10') else {
+        // messages are executed in order so the oldest one is the running one
+        let running = pending.values_mut().next();
+        match running {
+            Some(request) if line.starts_with("Uncaught ") => {
+                request.exception = Some(JsException {
+                    message: line,
+                    trace: Vec::new(),
+                })
+            }
+            Some(Pending {
+                exception: Some(exception),
+                ..
+            }) => exception.trace.push(line),
+            // nobody listening is fine
+            _ => {
+                comms.console.send(line).ok();
+            }
+        }
+        return;
+    };
+    if let Some(id) = tagged
+        .strip_prefix(END_TOKEN)
+        .and_then(|id| id.parse::<u32>().ok())
+    {
+        if let Some(request) = pending.remove(&id) {
+            // the next message only starts running now
+            if let Some(next) = pending.values_mut().next() {
+                next.last_activity = Instant::now();
+            }
+            let response = match request.exception {
+                Some(exception) => Err(exception),
+                None => Ok(request.lines),
+            };
+            // the requester might have given up
+            request.done.send(response).ok();
+        }
+    } else if let Some((request, data)) = tagged
+        .split_once(':')
+        .and_then(|(id, data)| Some((id.parse::<u32>().ok()?, data)))
+        .and_then(|(id, data)| Some((pending.get_mut(&id)?, data)))
+    {
+        request.lines.push(data.to_string());
+        if let Some(progress) = &request.progress {
+            progress(data.len());
+        }
+    } else {
+        comms.console.send(tagged.to_string()).ok();
     }
 }
 
+/// Process everything the watch sends, re-establishing the link when it is lost.
 pub async fn receive_messages(comms: Arc<Communicator>) -> Result<()> {
-    let (rx, command, receive_notifier) = (&comms.rx, &comms.command, &comms.receive_notifier);
-    let msgs = rx.notify().await?;
+    loop {
+        let lost = listen(&comms).await;
+        // whatever was running on the watch is gone with the link
+        comms.pending.lock().unwrap().clear();
+        comms.paused.store(false, Ordering::Relaxed);
+        comms.paused_notifier.notify_one();
+        if comms.closed.load(Ordering::Relaxed) {
+            return lost;
+        }
+        if let Err(e) = comms.transport.reconnect().await {
+            comms.closed.store(true, Ordering::Relaxed);
+            return Err(e.context("link to the watch lost"));
+        }
+    }
+}
+
+/// Route all received lines until the link goes down.
+async fn listen(comms: &Communicator) -> Result<()> {
+    let msgs = comms.transport.notifications().await?;
     msgs.map_ok(|mut v| {
         // pause or restart comms if we receive characters 17 or 19
         let mut pause_change = None;
@@ -101,106 +368,13 @@ pub async fn receive_messages(comms: Arc<Communicator>) -> Result<()> {
         // convert latin1 to utf8
         v.iter().map(|&b| b as char).collect::<String>()
     })
-    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    .map_err(std::io::Error::other)
     .into_async_read()
     .lines()
-    .try_fold(String::new(), |mut full_message, line| async move {
-        match command.lock().await.as_ref() {
-            None
-            | Some(Command::Run { .. })
-            | Some(Command::App { .. })
-            | Some(Command::Write { .. }) => {
-                if !line.starts_with('\x10') {
-                    println!("{line}");
-                    return Ok(full_message);
-                }
-            }
-            _ => (),
-        }
-
-        if line == END_TOKEN {
-            //TODO: avoid re-locking
-            if let Some(Command::Get { filename }) = command.lock().await.as_ref() {
-                let bytes = full_message
-                    .split_whitespace()
-                    .map(|l| l.parse::<u8>().map_err(|e| e.into()))
-                    .collect::<Result<Vec<u8>>>()
-                    .unwrap();
-                crate::utils::save_file(filename, &bytes)
-                    .await
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-            }
-            receive_notifier.notify_one();
-            full_message.clear();
-        } else {
-            full_message.extend(line.chars().skip(1));
-        }
-        Ok(full_message)
+    .try_for_each(|line| {
+        route(comms, line);
+        future::ready(Ok(()))
     })
     .await?;
     Ok(())
 }
-
-async fn find_banglejs(adapter: &Adapter) -> Result<Device> {
-    let nordic_uuid = Uuid::parse_str(NORDIC_UUID)?;
-    let mut connected_devices = adapter
-        .connected_devices_with_services(&[nordic_uuid])
-        .await?;
-    if let Some(device) = connected_devices.pop() {
-        println!("we are already connected");
-        return Ok(device);
-    }
-    println!("starting scan");
-    let mut scan = adapter.scan(&[]).await?;
-    println!("scan started");
-    while let Some(discovered_device) = scan.next().await {
-        if discovered_device
-            .device
-            .name()
-            .map(|n| n.starts_with("Bangle.js"))
-            .unwrap_or_default()
-            && discovered_device.adv_data.services.contains(&nordic_uuid)
-        {
-            println!("we found it !");
-            let device = discovered_device.device;
-
-            println!("connecting");
-            adapter.connect_device(&device).await?;
-            println!("connected");
-            while !device.is_paired().await? {
-                println!("we are not paired yet, trying pairing");
-                let mut l = String::new();
-                std::io::stdin().read_line(&mut l)?;
-                device.pair_with_agent(&StdioPairingAgent).await?;
-            }
-            println!("we are paired");
-            return Ok(device);
-        }
-    }
-    anyhow::bail!("no banglejs device found")
-}
-
-async fn tx_rx(bangle: &Device) -> Result<(Characteristic, Characteristic)> {
-    let nordic_uuid = Uuid::parse_str(NORDIC_UUID)?;
-    let nordic_tx_uuid = Uuid::parse_str(NORDIC_UART_TX_UUID)?;
-    let nordic_rx_uuid = Uuid::parse_str(NORDIC_UART_RX_UUID)?;
-
-    let services = bangle.discover_services_with_uuid(nordic_uuid).await?;
-    let service = services
-        .into_iter()
-        .find(|s| s.uuid() == nordic_uuid)
-        .ok_or_else(|| anyhow::anyhow!("no nordic service"))?;
-    let tx = service
-        .discover_characteristics_with_uuid(nordic_tx_uuid)
-        .await?
-        .into_iter()
-        .find(|c| c.uuid() == nordic_tx_uuid)
-        .ok_or_else(|| anyhow::anyhow!("no tx"))?;
-    let rx = service
-        .discover_characteristics_with_uuid(nordic_rx_uuid)
-        .await?
-        .into_iter()
-        .find(|c| c.uuid() == nordic_rx_uuid)
-        .ok_or_else(|| anyhow::anyhow!("no rx"))?;
-    Ok((tx, rx))
-}
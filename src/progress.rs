@@ -0,0 +1,103 @@
+use banglecomm::Progress;
+use std::io::IsTerminal;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const BAR_WIDTH: usize = 30;
+/// Minimal time between two events when we are not on a terminal.
+const EVENT_PERIOD: Duration = Duration::from_secs(1);
+
+/// Display transfers on stderr : a bar on a terminal, json events otherwise.
+pub struct TransferDisplay {
+    terminal: bool,
+    current: Mutex<Option<Transfer>>,
+}
+
+struct Transfer {
+    name: String,
+    start: Instant,
+    done: usize,
+    last_event: Option<Instant>,
+    finished: bool,
+}
+
+impl TransferDisplay {
+    pub fn new() -> Self {
+        TransferDisplay {
+            terminal: std::io::stderr().is_terminal(),
+            current: Mutex::new(None),
+        }
+    }
+}
+
+impl Progress for TransferDisplay {
+    fn update(&self, name: &str, done: usize, total: usize) {
+        let mut current = self.current.lock().unwrap();
+        let now = Instant::now();
+        let new_transfer = current
+            .as_ref()
+            .is_none_or(|t| t.name != name || done < t.done);
+        if new_transfer {
+            *current = Some(Transfer {
+                name: name.to_string(),
+                start: now,
+                done,
+                last_event: None,
+                finished: false,
+            });
+        }
+        let transfer = current.as_mut().unwrap();
+        if transfer.finished {
+            return;
+        }
+        transfer.done = done;
+        transfer.finished = done >= total;
+        let elapsed = (now - transfer.start).as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            done as f64 / elapsed
+        } else {
+            0.0
+        };
+        let eta = (rate > 0.0).then(|| (total - done) as f64 / rate);
+        let percent = (done * 100).checked_div(total).unwrap_or(100);
+        if self.terminal {
+            let filled = (BAR_WIDTH * done).checked_div(total).unwrap_or(BAR_WIDTH);
+            eprint!(
+                "\r{name} [{}{}] {percent:3}% {}/s ETA {}   ",
+                "#".repeat(filled),
+                " ".repeat(BAR_WIDTH - filled),
+                human_size(rate),
+                eta.map(|eta| format!("{eta:.0}s"))
+                    .unwrap_or("?".to_string())
+            );
+            if transfer.finished {
+                eprintln!();
+            }
+        } else if transfer.finished
+            || transfer
+                .last_event
+                .is_none_or(|last| now - last >= EVENT_PERIOD)
+        {
+            transfer.last_event = Some(now);
+            let event = serde_json::json!({
+                "event": "progress",
+                "name": name,
+                "done": done,
+                "total": total,
+                "bytes_per_second": rate.round(),
+                "eta_seconds": eta.map(f64::round),
+            });
+            eprintln!("{event}");
+        }
+    }
+}
+
+pub fn human_size(bytes: f64) -> String {
+    if bytes >= 1_000_000.0 {
+        format!("{:.1} MB", bytes / 1_000_000.0)
+    } else if bytes >= 1_000.0 {
+        format!("{:.1} kB", bytes / 1_000.0)
+    } else {
+        format!("{bytes:.0} B")
+    }
+}
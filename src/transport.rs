@@ -0,0 +1,31 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use futures_util::stream::BoxStream;
+
+pub mod ble;
+#[cfg(feature = "fake")]
+pub mod fake;
+pub mod process;
+pub mod serial;
+pub mod stream;
+pub mod tcp;
+
+/// A raw byte link to an espruino device.
+///
+/// Implementations only move bytes around : the espruino protocol
+/// (end tokens, XON/XOFF flow control) is handled by the `Communicator` on top of it.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// Maximal number of bytes accepted by one call to `write`.
+    fn max_write_len(&self) -> Result<usize>;
+    /// Send given bytes to the device.
+    async fn write(&self, bytes: &[u8]) -> Result<()>;
+    /// Stream of all bytes received from the device.
+    async fn notifications(&self) -> Result<BoxStream<'_, Result<Vec<u8>>>>;
+    /// Close the link.
+    async fn disconnect(&self) -> Result<()>;
+    /// Re-establish a lost link, after which `notifications` can be called again.
+    async fn reconnect(&self) -> Result<()> {
+        anyhow::bail!("this link can't be re-established")
+    }
+}
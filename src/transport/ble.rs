@@ -0,0 +1,350 @@
+use super::Transport;
+use crate::pairing::StdioPairingAgent;
+use anyhow::Result;
+use async_trait::async_trait;
+use bluest::{Adapter, Characteristic, ConnectionEvent, Device, Uuid};
+use futures_util::{stream::BoxStream, StreamExt};
+use itertools::Itertools;
+use serde::Serialize;
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+const NORDIC_UUID: &str = "6e400001-b5a3-f393-e0a9-e50e24dcca9e";
+const NORDIC_UART_TX_UUID: &str = "6e400002-b5a3-f393-e0a9-e50e24dcca9e";
+const NORDIC_UART_RX_UUID: &str = "6e400003-b5a3-f393-e0a9-e50e24dcca9e";
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// How long we keep scanning for other candidates after finding a watch.
+const CANDIDATES_WINDOW: Duration = Duration::from_secs(2);
+
+/// Nordic UART service over bluetooth low energy.
+pub struct BleTransport {
+    adapter: Adapter,
+    /// Replaced when we reconnect.
+    link: RwLock<Link>,
+}
+
+#[derive(Clone)]
+struct Link {
+    rx: Characteristic,
+    tx: Characteristic,
+    bangle: Device,
+}
+
+impl BleTransport {
+    /// Connect to the watch with given name (or part of it) or address.
+    ///
+    /// Without a query we connect to the only banglejs around.
+    pub async fn new(query: Option<&str>) -> Result<Self> {
+        // open bluetooth
+        let adapter = Adapter::default()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Bluetooth adapter not found"))?;
+        adapter.wait_available().await?;
+
+        let link = connect(&adapter, query).await?;
+        Ok(BleTransport {
+            adapter,
+            link: RwLock::new(link),
+        })
+    }
+
+    /// Address of the watch we are talking to.
+    pub fn address(&self) -> String {
+        address(&self.link.read().unwrap().bangle)
+    }
+
+    fn link(&self) -> Link {
+        self.link.read().unwrap().clone()
+    }
+}
+
+async fn connect(adapter: &Adapter, query: Option<&str>) -> Result<Link> {
+    // find the watch
+    let bangle = find_banglejs(adapter, query).await?;
+
+    // get the communication channels from the watch
+    let (tx, rx) = tx_rx(&bangle).await?;
+
+    Ok(Link { rx, tx, bangle })
+}
+
+#[async_trait]
+impl Transport for BleTransport {
+    fn max_write_len(&self) -> Result<usize> {
+        Ok(self.link.read().unwrap().tx.max_write_len()?)
+    }
+
+    async fn write(&self, bytes: &[u8]) -> Result<()> {
+        self.link().tx.write(bytes).await?;
+        Ok(())
+    }
+
+    async fn notifications(&self) -> Result<BoxStream<'_, Result<Vec<u8>>>> {
+        // the notifications borrow the characteristic, which a reconnection replaces,
+        // so we forward them from a task owning it
+        let (adapter, Link { rx, bangle, .. }) = (self.adapter.clone(), self.link());
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (subscribed, subscription) = oneshot::channel();
+        tokio::spawn(async move {
+            let subscribe = async {
+                let events = adapter.device_connection_events(&bangle).await?;
+                let msgs = rx.notify().await?;
+                Ok::<_, bluest::Error>((events, msgs))
+            };
+            let (mut events, mut msgs) = match subscribe.await {
+                Ok(streams) => streams,
+                Err(e) => {
+                    subscribed.send(Err(e)).ok();
+                    return;
+                }
+            };
+            subscribed.send(Ok(())).ok();
+            // the stream ends when the watch goes away
+            loop {
+                tokio::select! {
+                    msg = msgs.next() => {
+                        let Some(msg) = msg else { break };
+                        if sender.send(msg.map_err(|e| e.into())).is_err() {
+                            break;
+                        }
+                    }
+                    event = events.next() => {
+                        if matches!(event, None | Some(ConnectionEvent::Disconnected)) {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        subscription.await??;
+        let stream = futures_util::stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|msg| (msg, receiver))
+        });
+        Ok(stream.boxed())
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        eprintln!("disconnecting");
+        self.adapter.disconnect_device(&self.link().bangle).await?;
+        Ok(())
+    }
+
+    async fn reconnect(&self) -> Result<()> {
+        // stick to the same watch
+        let address = self.address();
+        loop {
+            eprintln!("reconnecting");
+            match connect(&self.adapter, Some(&address)).await {
+                Ok(link) => {
+                    *self.link.write().unwrap() = link;
+                    eprintln!("reconnected");
+                    return Ok(());
+                }
+                Err(e) => {
+                    eprintln!("reconnecting failed ({e}), retrying in {RECONNECT_DELAY:?}");
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    }
+}
+
+/// Names advertised by espruino boards.
+const ESPRUINO_NAMES: [&str; 5] = ["Bangle.js", "Espruino", "Puck.js", "Pixl.js", "MDBT42Q"];
+
+/// An espruino device seen during a scan.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScannedDevice {
+    pub name: String,
+    pub address: String,
+    /// Signal strength in dBm, unknown for already connected devices.
+    pub rssi: Option<i16>,
+    pub services: Vec<String>,
+    pub paired: bool,
+    pub connected: bool,
+}
+
+/// List espruino devices around, scanning for given duration.
+pub async fn scan(duration: Duration) -> Result<Vec<ScannedDevice>> {
+    let adapter = Adapter::default()
+        .await
+        .ok_or_else(|| anyhow::anyhow!("Bluetooth adapter not found"))?;
+    adapter.wait_available().await?;
+    let nordic_uuid = Uuid::parse_str(NORDIC_UUID)?;
+
+    // connected devices don't advertise anymore
+    let mut found: Vec<ScannedDevice> = Vec::new();
+    for device in adapter
+        .connected_devices_with_services(&[nordic_uuid])
+        .await?
+    {
+        found.push(ScannedDevice {
+            name: device.name().unwrap_or_default(),
+            address: address(&device),
+            rssi: None,
+            services: vec![nordic_uuid.to_string()],
+            paired: device.is_paired().await?,
+            connected: true,
+        });
+    }
+
+    let mut scan = adapter.scan(&[]).await?;
+    let deadline = Instant::now() + duration;
+    while let Ok(Some(discovered_device)) = tokio::time::timeout_at(deadline, scan.next()).await {
+        let device = &discovered_device.device;
+        let name = device
+            .name()
+            .ok()
+            .or(discovered_device.adv_data.local_name)
+            .unwrap_or_default();
+        let services = &discovered_device.adv_data.services;
+        if !services.contains(&nordic_uuid) && !ESPRUINO_NAMES.iter().any(|n| name.starts_with(n)) {
+            continue;
+        }
+        let address = address(device);
+        let services = services.iter().map(|s| s.to_string());
+        // devices advertise repeatedly, keep the latest news
+        if let Some(seen) = found.iter_mut().find(|d| d.address == address) {
+            seen.rssi = discovered_device.rssi.or(seen.rssi);
+            for service in services {
+                if !seen.services.contains(&service) {
+                    seen.services.push(service);
+                }
+            }
+            continue;
+        }
+        found.push(ScannedDevice {
+            name,
+            address,
+            rssi: discovered_device.rssi,
+            services: services.collect(),
+            paired: device.is_paired().await?,
+            connected: device.is_connected().await,
+        });
+    }
+    Ok(found)
+}
+
+/// Printable address of given device.
+pub fn address(device: &Device) -> String {
+    // bluest only gives us a debug representation : DeviceId(AA:BB:CC:DD:EE:FF)
+    let id = format!("{:?}", device.id());
+    id.strip_prefix("DeviceId(")
+        .and_then(|id| id.strip_suffix(')'))
+        .map(|id| id.to_string())
+        .unwrap_or(id)
+}
+
+/// Is given device the one the user asked for ?
+fn matches(name: &str, address: &str, query: Option<&str>) -> bool {
+    query.is_none_or(|query| {
+        address.eq_ignore_ascii_case(query) || name.to_lowercase().contains(&query.to_lowercase())
+    })
+}
+
+fn ambiguous(candidates: &[Device]) -> anyhow::Error {
+    let list = candidates
+        .iter()
+        .map(|d| format!("  {} ({})", d.name().unwrap_or_default(), address(d)))
+        .join("\n");
+    anyhow::anyhow!("several watches found, choose one by name or address:\n{list}")
+}
+
+async fn find_banglejs(adapter: &Adapter, query: Option<&str>) -> Result<Device> {
+    let nordic_uuid = Uuid::parse_str(NORDIC_UUID)?;
+    let mut connected_devices: Vec<Device> = adapter
+        .connected_devices_with_services(&[nordic_uuid])
+        .await?
+        .into_iter()
+        .filter(|d| matches(&d.name().unwrap_or_default(), &address(d), query))
+        .collect();
+    if connected_devices.len() > 1 {
+        return Err(ambiguous(&connected_devices));
+    }
+    if let Some(device) = connected_devices.pop() {
+        eprintln!("we are already connected");
+        return Ok(device);
+    }
+    eprintln!("starting scan");
+    let mut scan = adapter.scan(&[]).await?;
+    eprintln!("scan started");
+    // once we found a watch we keep looking a little for others
+    let mut deadline = None;
+    let mut candidates: Vec<Device> = Vec::new();
+    loop {
+        let discovered_device = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, scan.next()).await {
+                Ok(discovered_device) => discovered_device,
+                Err(_) => break,
+            },
+            None => scan.next().await,
+        };
+        let Some(discovered_device) = discovered_device else {
+            break;
+        };
+        let device = discovered_device.device;
+        let name = device.name().unwrap_or_default();
+        let address = address(&device);
+        if !name.starts_with("Bangle.js")
+            || !discovered_device.adv_data.services.contains(&nordic_uuid)
+            || !matches(&name, &address, query)
+            || candidates.contains(&device)
+        {
+            continue;
+        }
+        eprintln!("we found {name} ({address}) !");
+        if query.is_some_and(|query| query == name || query.eq_ignore_ascii_case(&address)) {
+            // no doubt possible
+            candidates = vec![device];
+            break;
+        }
+        candidates.push(device);
+        deadline.get_or_insert_with(|| Instant::now() + CANDIDATES_WINDOW);
+    }
+    drop(scan);
+    if candidates.len() > 1 {
+        return Err(ambiguous(&candidates));
+    }
+    let device = candidates
+        .pop()
+        .ok_or_else(|| anyhow::anyhow!("no banglejs device found"))?;
+
+    eprintln!("connecting");
+    adapter.connect_device(&device).await?;
+    eprintln!("connected");
+    while !device.is_paired().await? {
+        eprintln!("we are not paired yet, trying pairing");
+        let mut l = String::new();
+        std::io::stdin().read_line(&mut l)?;
+        device.pair_with_agent(&StdioPairingAgent).await?;
+    }
+    eprintln!("we are paired");
+    Ok(device)
+}
+
+async fn tx_rx(bangle: &Device) -> Result<(Characteristic, Characteristic)> {
+    let nordic_uuid = Uuid::parse_str(NORDIC_UUID)?;
+    let nordic_tx_uuid = Uuid::parse_str(NORDIC_UART_TX_UUID)?;
+    let nordic_rx_uuid = Uuid::parse_str(NORDIC_UART_RX_UUID)?;
+
+    let services = bangle.discover_services_with_uuid(nordic_uuid).await?;
+    let service = services
+        .into_iter()
+        .find(|s| s.uuid() == nordic_uuid)
+        .ok_or_else(|| anyhow::anyhow!("no nordic service"))?;
+    let tx = service
+        .discover_characteristics_with_uuid(nordic_tx_uuid)
+        .await?
+        .into_iter()
+        .find(|c| c.uuid() == nordic_tx_uuid)
+        .ok_or_else(|| anyhow::anyhow!("no tx"))?;
+    let rx = service
+        .discover_characteristics_with_uuid(nordic_rx_uuid)
+        .await?
+        .into_iter()
+        .find(|c| c.uuid() == nordic_rx_uuid)
+        .ok_or_else(|| anyhow::anyhow!("no rx"))?;
+    Ok((tx, rx))
+}
@@ -0,0 +1,644 @@
+use super::Transport;
+use crate::heatshrink;
+use anyhow::Result;
+use async_trait::async_trait;
+use futures_util::{stream::BoxStream, StreamExt};
+use itertools::Itertools;
+use js::{Host, Interpreter, JsError, JsResult, Value};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+mod js;
+
+const MAX_FILENAME_LEN: usize = 28;
+/// Bytes in each chunk file of a StorageFile (much more on a real watch).
+const STORAGE_FILE_CHUNK_SIZE: usize = 256;
+/// Bytes of flash available to `Storage`.
+const FLASH_SIZE: usize = 1 << 20;
+/// How long we keep the sender paused after XOFF.
+const FLOW_CONTROL_PAUSE: Duration = Duration::from_millis(20);
+
+/// In-process simulated watch, running the javascript we send on an in-memory flash.
+///
+/// Clones share the same watch so tests can keep a handle on it
+/// while the `Communicator` owns the transport.
+#[derive(Clone)]
+pub struct FakeWatch {
+    state: Arc<Mutex<State>>,
+    link: Arc<Mutex<Link>>,
+}
+
+/// Channel carrying what the watch sends, replaced when we reconnect.
+struct Link {
+    /// `None` while out of range.
+    output: Option<UnboundedSender<Vec<u8>>>,
+    receiver: Option<UnboundedReceiver<Vec<u8>>>,
+}
+
+impl Link {
+    fn new() -> Self {
+        let (output, receiver) = unbounded_channel();
+        Link {
+            output: Some(output),
+            receiver: Some(receiver),
+        }
+    }
+}
+
+struct State {
+    interpreter: Interpreter,
+    device: Device,
+    /// Current input line.
+    line: Vec<u8>,
+    /// Does current line start with `\x10` ?
+    silent: bool,
+    /// Send XOFF when more than this number of bytes arrive without a pause.
+    flow_control: Option<usize>,
+    received: usize,
+    /// Between XOFF and XON.
+    paused: bool,
+    /// Bytes which arrived while paused.
+    overrun: usize,
+    packet_size: usize,
+    /// Time each packet we send back takes to arrive.
+    latency: Duration,
+    /// A hung (or rebooting) watch ignores everything.
+    responsive: bool,
+}
+
+/// What the javascript can see of the watch.
+#[derive(Default)]
+struct Device {
+    flash: BTreeMap<String, Vec<u8>>,
+    time: Option<f64>,
+    console: String,
+    /// Number of upcoming `Storage.write` to damage.
+    corrupted_writes: usize,
+    /// Number of upcoming `Storage.write` to leave intact before damaging any.
+    intact_writes: usize,
+    /// Number of upcoming reply lines to damage.
+    garbled_replies: usize,
+    /// Number of upcoming reply lines to leave intact before damaging any.
+    intact_replies: usize,
+    /// Sizes of erased or replaced files, still taking space until compaction.
+    trash: Vec<usize>,
+}
+
+impl Default for FakeWatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FakeWatch {
+    pub fn new() -> Self {
+        FakeWatch {
+            state: Arc::new(Mutex::new(State {
+                interpreter: Interpreter::default(),
+                device: Device::default(),
+                line: Vec::new(),
+                silent: false,
+                flow_control: None,
+                received: 0,
+                paused: false,
+                overrun: 0,
+                packet_size: 20,
+                latency: Duration::ZERO,
+                responsive: true,
+            })),
+            link: Arc::new(Mutex::new(Link::new())),
+        }
+    }
+
+    /// Pause the sender with XOFF/XON every time more than `threshold` bytes arrive.
+    pub fn with_flow_control(self, threshold: usize) -> Self {
+        self.state.lock().unwrap().flow_control = Some(threshold);
+        self
+    }
+
+    /// Split everything we send back into packets of given size (20 like bluetooth by default).
+    pub fn with_packet_size(self, packet_size: usize) -> Self {
+        self.state.lock().unwrap().packet_size = packet_size.max(1);
+        self
+    }
+
+    /// Delay each packet we send back, like a slow link or a busy watch.
+    pub fn with_latency(self, latency: Duration) -> Self {
+        self.state.lock().unwrap().latency = latency;
+        self
+    }
+
+    /// Number of bytes sent to us while we asked for a pause.
+    pub fn overrun(&self) -> usize {
+        self.state.lock().unwrap().overrun
+    }
+
+    /// Simulate a hung watch : everything we receive is lost.
+    pub fn set_responsive(&self, responsive: bool) {
+        self.state.lock().unwrap().responsive = responsive;
+    }
+
+    /// Simulate the watch going out of range : the link is lost until we reconnect.
+    pub fn drop_link(&self) {
+        *self.link.lock().unwrap() = Link {
+            output: None,
+            receiver: None,
+        };
+    }
+
+    /// Damage the data of the next `count` writes to the flash, like lost packets.
+    pub fn corrupt_writes(&self, count: usize) {
+        self.corrupt_writes_after(0, count);
+    }
+
+    /// Damage `count` writes to the flash once `intact` more went through.
+    pub fn corrupt_writes_after(&self, intact: usize, count: usize) {
+        let device = &mut self.state.lock().unwrap().device;
+        device.intact_writes = intact;
+        device.corrupted_writes = count;
+    }
+
+    /// Damage the data of `count` reply lines once `intact` more went through, like lost bytes.
+    pub fn garble_replies_after(&self, intact: usize, count: usize) {
+        let device = &mut self.state.lock().unwrap().device;
+        device.intact_replies = intact;
+        device.garbled_replies = count;
+    }
+
+    pub fn file(&self, filename: &str) -> Option<Vec<u8>> {
+        self.state
+            .lock()
+            .unwrap()
+            .device
+            .flash
+            .get(filename)
+            .cloned()
+    }
+
+    pub fn set_file(&self, filename: &str, content: &[u8]) {
+        self.state
+            .lock()
+            .unwrap()
+            .device
+            .flash
+            .insert(filename.to_string(), content.to_vec());
+    }
+
+    /// Content of given StorageFile, reassembled from its chunks.
+    pub fn storage_file(&self, name: &str) -> Vec<u8> {
+        self.state.lock().unwrap().device.storage_file(name)
+    }
+
+    pub fn set_storage_file(&self, name: &str, content: &[u8]) {
+        let device = &mut self.state.lock().unwrap().device;
+        device.storage_file_erase(name);
+        device.storage_file_append(name, content);
+    }
+
+    pub fn files(&self) -> Vec<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .device
+            .flash
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    /// Last time set with `setTime`, in seconds.
+    pub fn time(&self) -> Option<f64> {
+        self.state.lock().unwrap().device.time
+    }
+
+    fn send(&self, bytes: &[u8], packet_size: usize) {
+        let link = self.link.lock().unwrap();
+        for packet in bytes.chunks(packet_size) {
+            // if nobody listens anymore we just talk to ourselves, like a real watch
+            if let Some(output) = &link.output {
+                output.send(packet.to_vec()).ok();
+            }
+        }
+    }
+}
+
+impl State {
+    /// Feed bytes to espruino's line editor, returning the console output.
+    fn receive(&mut self, bytes: &[u8]) -> String {
+        for &byte in bytes {
+            match byte {
+                // ctrl-c clears the input line
+                3 => self.line.clear(),
+                16 if self.line.is_empty() => self.silent = true,
+                16 | b'\r' => (),
+                b'\n' if is_complete(&self.line) => {
+                    let line = std::mem::take(&mut self.line);
+                    let code: String = line.iter().map(|&b| b as char).collect();
+                    self.execute(&code);
+                    self.silent = false;
+                }
+                b => self.line.push(b),
+            }
+        }
+        std::mem::take(&mut self.device.console)
+    }
+
+    fn execute(&mut self, code: &str) {
+        if !self.silent {
+            self.device.console.push_str(code);
+            self.device.console.push_str("\r\n");
+        }
+        match self.interpreter.execute(code, &mut self.device) {
+            Ok(value) => {
+                if !self.silent {
+                    let value = value.to_json().unwrap_or_else(|| value.to_js_string());
+                    self.device.console.push_str(&format!("={value}\r\n"));
+                }
+            }
+            Err(error) => self.device.console.push_str(&format!(
+                "Uncaught {}\r\n at line 1 col 1\r\n",
+                error.description()
+            )),
+        }
+    }
+}
+
+/// Are all brackets of this (partial) line closed ?
+fn is_complete(line: &[u8]) -> bool {
+    let mut depth = 0i32;
+    let mut quote = None;
+    let mut escaped = false;
+    for &b in line {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if b == b'\\' {
+                escaped = true;
+            } else if b == q {
+                quote = None;
+            }
+            continue;
+        }
+        match b {
+            b'"' | b'\'' | b'`' => quote = Some(b),
+            b'(' | b'[' | b'{' => depth += 1,
+            b')' | b']' | b'}' => depth -= 1,
+            _ => (),
+        }
+    }
+    depth <= 0 && quote.is_none()
+}
+
+fn latin1(bytes: &[u8]) -> Value {
+    Value::String(bytes.iter().map(|&b| b as char).collect())
+}
+
+fn storage() -> Value {
+    Value::object(
+        [
+            ("write", "Storage.write"),
+            ("writeJSON", "Storage.writeJSON"),
+            ("read", "Storage.read"),
+            ("readArrayBuffer", "Storage.readArrayBuffer"),
+            ("list", "Storage.list"),
+            ("erase", "Storage.erase"),
+            ("open", "Storage.open"),
+            ("getStats", "Storage.getStats"),
+            ("compact", "Storage.compact"),
+        ]
+        .into_iter()
+        .map(|(method, builtin)| (method, Value::Builtin(builtin)))
+        .collect(),
+    )
+}
+
+/// StorageFile chunks are stored as `name\x01`, `name\x02`...
+fn storage_file_chunk(name: &str, index: usize) -> String {
+    format!("{name}{}", (index + 1) as u8 as char)
+}
+
+/// Name of the StorageFile given file is a chunk of.
+fn storage_file_name(filename: &str) -> Option<&str> {
+    filename
+        .strip_suffix(|c: char| (c as u32) < 32)
+        .filter(|name| !name.is_empty())
+}
+
+impl Device {
+    /// Erase given file, leaving its space in the trash.
+    fn remove(&mut self, filename: &str) -> Option<Vec<u8>> {
+        let content = self.flash.remove(filename)?;
+        self.trash.push(content.len());
+        Some(content)
+    }
+
+    /// Content of all chunks of given StorageFile.
+    fn storage_file(&self, name: &str) -> Vec<u8> {
+        (0..)
+            .map_while(|index| self.flash.get(&storage_file_chunk(name, index)))
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    fn storage_file_append(&mut self, name: &str, data: &[u8]) {
+        let mut content = self.storage_file(name);
+        content.extend(data);
+        for (index, chunk) in content.chunks(STORAGE_FILE_CHUNK_SIZE).enumerate() {
+            self.flash
+                .insert(storage_file_chunk(name, index), chunk.to_vec());
+        }
+    }
+
+    fn storage_file_erase(&mut self, name: &str) {
+        let mut index = 0;
+        while self.remove(&storage_file_chunk(name, index)).is_some() {
+            index += 1;
+        }
+    }
+
+    fn storage_write(&mut self, args: &[Value]) -> JsResult<Value> {
+        let arg = |i: usize| args.get(i).cloned().unwrap_or(Value::Undefined);
+        let filename = arg(0).to_js_string();
+        if filename.is_empty() || filename.len() > MAX_FILENAME_LEN {
+            return Err(JsError::new("Error", "Invalid filename"));
+        }
+        let mut data = arg(1).to_bytes();
+        if self.intact_writes > 0 {
+            self.intact_writes -= 1;
+        } else if self.corrupted_writes > 0 && !data.is_empty() {
+            self.corrupted_writes -= 1;
+            data[0] ^= 0xff;
+        }
+        let offset = match arg(2) {
+            Value::Undefined => 0,
+            v => v.to_number() as usize,
+        };
+        let file = match arg(3) {
+            // a new file of given size, filled with 0xFF like erased flash
+            Value::Number(size) => {
+                self.remove(&filename);
+                let file = vec![0xff; size as usize];
+                self.flash.insert(filename.clone(), file);
+                self.flash.get_mut(&filename).unwrap()
+            }
+            _ if offset == 0 => {
+                self.remove(&filename);
+                self.flash.insert(filename, data);
+                return Ok(Value::Bool(true));
+            }
+            _ => self
+                .flash
+                .get_mut(&filename)
+                .ok_or_else(|| JsError::new("Error", "File not found"))?,
+        };
+        if offset + data.len() > file.len() {
+            return Err(JsError::new("Error", "Too much data for file size"));
+        }
+        // flash bits can only be cleared, written bytes stay as they are
+        if file[offset..offset + data.len()].iter().any(|&b| b != 0xff) {
+            return Err(JsError::new("Error", "Can't write over written data"));
+        }
+        file[offset..offset + data.len()].copy_from_slice(&data);
+        Ok(Value::Bool(true))
+    }
+}
+
+impl Host for Device {
+    fn print(&mut self, text: &str) {
+        // replies are "\x10<id>:<data>", end tokens have no colon
+        if let Some((id, data)) = text.strip_prefix('\x10').and_then(|t| t.split_once(':')) {
+            if self.intact_replies > 0 {
+                self.intact_replies -= 1;
+            } else if self.garbled_replies > 0 {
+                self.garbled_replies -= 1;
+                self.console.push_str(&format!("\x10{id}:!{data}"));
+                return;
+            }
+        }
+        self.console.push_str(text);
+    }
+
+    fn global(&mut self, name: &str) -> Option<Value> {
+        Some(match name {
+            "require" => Value::Builtin("require"),
+            "setTime" => Value::Builtin("setTime"),
+            "E" => Value::object(vec![("CRC32", Value::Builtin("E.CRC32"))]),
+            _ => return None,
+        })
+    }
+
+    fn call(&mut self, builtin: &'static str, this: &Value, args: &[Value]) -> JsResult<Value> {
+        let arg = |i: usize| args.get(i).cloned().unwrap_or(Value::Undefined);
+        Ok(match builtin {
+            "require" => match arg(0).to_js_string().as_str() {
+                "Storage" => storage(),
+                "heatshrink" => Value::object(vec![(
+                    "decompress",
+                    Value::Builtin("heatshrink.decompress"),
+                )]),
+                module => {
+                    return Err(JsError::new(
+                        "Error",
+                        &format!("Module {module:?} not found"),
+                    ))
+                }
+            },
+            "setTime" => {
+                self.time = Some(arg(0).to_number());
+                Value::Undefined
+            }
+            "heatshrink.decompress" => Value::bytes(heatshrink::decompress(&arg(0).to_bytes())),
+            "E.CRC32" => Value::Number(crc32fast::hash(&arg(0).to_bytes()) as f64),
+            "Storage.write" => self.storage_write(args)?,
+            "Storage.writeJSON" => {
+                let json = arg(1).to_json().unwrap_or_default();
+                self.storage_write(&[arg(0), Value::String(json)])?
+            }
+            "Storage.read" => match self.flash.get(&arg(0).to_js_string()) {
+                Some(content) => {
+                    let offset = match arg(1) {
+                        Value::Undefined => 0,
+                        v => (v.to_number() as usize).min(content.len()),
+                    };
+                    let end = match arg(2) {
+                        Value::Undefined => content.len(),
+                        v => (offset + v.to_number() as usize).min(content.len()),
+                    };
+                    latin1(&content[offset..end])
+                }
+                None => Value::Undefined,
+            },
+            "Storage.readArrayBuffer" => self
+                .flash
+                .get(&arg(0).to_js_string())
+                .map(|content| Value::bytes(content.clone()))
+                .unwrap_or(Value::Undefined),
+            "Storage.list" => {
+                let names: Vec<&str> = match arg(1).get("sf") {
+                    Value::Undefined => self.flash.keys().map(|k| k.as_str()).collect(),
+                    Value::Bool(true) => self
+                        .flash
+                        .keys()
+                        .filter_map(|k| storage_file_name(k))
+                        .dedup()
+                        .collect(),
+                    _ => self
+                        .flash
+                        .keys()
+                        .filter(|k| storage_file_name(k).is_none())
+                        .map(|k| k.as_str())
+                        .collect(),
+                };
+                Value::array(names.into_iter().map(Value::from).collect())
+            }
+            "Storage.open" => {
+                let name = arg(0).to_js_string();
+                if name.is_empty() || name.len() >= MAX_FILENAME_LEN {
+                    return Err(JsError::new("Error", "Invalid filename"));
+                }
+                let mode = arg(1).to_js_string();
+                match mode.as_str() {
+                    "w" => self.storage_file_erase(&name),
+                    "r" | "a" => (),
+                    _ => return Err(JsError::new("Error", "Invalid mode")),
+                }
+                Value::object(vec![
+                    ("name", Value::String(name)),
+                    ("offset", Value::Number(0.0)),
+                    ("read", Value::Builtin("StorageFile.read")),
+                    ("write", Value::Builtin("StorageFile.write")),
+                    ("erase", Value::Builtin("StorageFile.erase")),
+                    ("getLength", Value::Builtin("StorageFile.getLength")),
+                ])
+            }
+            "StorageFile.read" => {
+                let content = self.storage_file(&this.get("name").to_js_string());
+                let offset = this.get("offset").to_number() as usize;
+                if offset >= content.len() {
+                    Value::Undefined
+                } else {
+                    let end = (offset + arg(0).to_number() as usize).min(content.len());
+                    this.set("offset", Value::Number(end as f64));
+                    latin1(&content[offset..end])
+                }
+            }
+            "StorageFile.write" => {
+                self.storage_file_append(&this.get("name").to_js_string(), &arg(0).to_bytes());
+                Value::Undefined
+            }
+            "StorageFile.erase" => {
+                self.storage_file_erase(&this.get("name").to_js_string());
+                Value::Undefined
+            }
+            "StorageFile.getLength" => {
+                Value::Number(self.storage_file(&this.get("name").to_js_string()).len() as f64)
+            }
+            "Storage.erase" => {
+                self.remove(&arg(0).to_js_string());
+                Value::Undefined
+            }
+            "Storage.getStats" => {
+                let file_bytes: usize = self.flash.values().map(|f| f.len()).sum();
+                let trash_bytes: usize = self.trash.iter().sum();
+                Value::object(vec![
+                    ("totalBytes", Value::Number(FLASH_SIZE as f64)),
+                    (
+                        "freeBytes",
+                        Value::Number((FLASH_SIZE - file_bytes - trash_bytes) as f64),
+                    ),
+                    ("fileBytes", Value::Number(file_bytes as f64)),
+                    ("fileCount", Value::Number(self.flash.len() as f64)),
+                    ("trashBytes", Value::Number(trash_bytes as f64)),
+                    ("trashCount", Value::Number(self.trash.len() as f64)),
+                ])
+            }
+            "Storage.compact" => {
+                self.trash.clear();
+                Value::Undefined
+            }
+            _ => {
+                return Err(JsError::new(
+                    "TypeError",
+                    &format!("{builtin} is not a function"),
+                ))
+            }
+        })
+    }
+}
+
+#[async_trait]
+impl Transport for FakeWatch {
+    fn max_write_len(&self) -> Result<usize> {
+        Ok(128)
+    }
+
+    async fn write(&self, bytes: &[u8]) -> Result<()> {
+        anyhow::ensure!(
+            self.link.lock().unwrap().output.is_some(),
+            "fake watch is out of range"
+        );
+        let mut state = self.state.lock().unwrap();
+        if !state.responsive {
+            return Ok(());
+        }
+        let packet_size = state.packet_size;
+        if state.paused {
+            state.overrun += bytes.len();
+        }
+        state.received += bytes.len();
+        let pause = !state.paused
+            && state
+                .flow_control
+                .is_some_and(|threshold| state.received > threshold);
+        if pause {
+            // XOFF, then XON once we had time to process everything
+            state.paused = true;
+            self.send(&[19], packet_size);
+            let watch = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(FLOW_CONTROL_PAUSE).await;
+                let mut state = watch.state.lock().unwrap();
+                state.paused = false;
+                state.received = 0;
+                watch.send(&[17], state.packet_size);
+            });
+        }
+        let console = state.receive(bytes);
+        self.send(
+            &console.chars().map(|c| c as u32 as u8).collect::<Vec<_>>(),
+            packet_size,
+        );
+        Ok(())
+    }
+
+    async fn notifications(&self) -> Result<BoxStream<'_, Result<Vec<u8>>>> {
+        let receiver = self
+            .link
+            .lock()
+            .unwrap()
+            .receiver
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("fake watch is already being listened to"))?;
+        let latency = self.state.lock().unwrap().latency;
+        let stream = futures_util::stream::unfold(receiver, move |mut receiver| async move {
+            let bytes = receiver.recv().await?;
+            if !latency.is_zero() {
+                tokio::time::sleep(latency).await;
+            }
+            Some((Ok(bytes), receiver))
+        });
+        Ok(stream.boxed())
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn reconnect(&self) -> Result<()> {
+        *self.link.lock().unwrap() = Link::new();
+        Ok(())
+    }
+}
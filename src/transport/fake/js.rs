@@ -0,0 +1,1538 @@
+//! Tiny javascript interpreter, just big enough to run the snippets we send to the watch.
+//!
+//! Everything espruino specific (Storage, E, setTime...) is provided by a `Host`.
+use base64::prelude::*;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+pub type Shared<T> = Arc<Mutex<T>>;
+
+#[derive(Clone, Debug)]
+pub enum Value {
+    Undefined,
+    Null,
+    Bool(bool),
+    Number(f64),
+    /// Strings are latin1 : each char is a byte.
+    String(String),
+    Array(Shared<Vec<Value>>),
+    Object(Shared<Vec<(String, Value)>>),
+    /// ArrayBuffer and Uint8Array.
+    Bytes(Shared<Vec<u8>>),
+    Function(Arc<Function>),
+    Builtin(&'static str),
+}
+
+#[derive(Debug)]
+pub struct Function {
+    definition: Arc<FunctionDefinition>,
+    scope: Arc<Scope>,
+}
+
+/// A thrown javascript value.
+#[derive(Debug)]
+pub struct JsError(pub Value);
+
+impl JsError {
+    pub fn new(kind: &str, message: &str) -> Self {
+        JsError(Value::object(vec![
+            ("name", Value::from(kind)),
+            ("message", Value::from(message)),
+        ]))
+    }
+
+    /// Text displayed by espruino after "Uncaught ".
+    pub fn description(&self) -> String {
+        match self.0.get("message") {
+            Value::Undefined => self.0.to_js_string(),
+            message => format!(
+                "{}: {}",
+                self.0.get("name").to_js_string(),
+                message.to_js_string()
+            ),
+        }
+    }
+}
+
+pub type JsResult<T> = Result<T, JsError>;
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Number(n)
+    }
+}
+
+impl Value {
+    pub fn object(fields: Vec<(&str, Value)>) -> Self {
+        Value::Object(Arc::new(Mutex::new(
+            fields
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        )))
+    }
+
+    pub fn array(values: Vec<Value>) -> Self {
+        Value::Array(Arc::new(Mutex::new(values)))
+    }
+
+    pub fn bytes(bytes: Vec<u8>) -> Self {
+        Value::Bytes(Arc::new(Mutex::new(bytes)))
+    }
+
+    /// Property lookup on objects, `Undefined` for everything else.
+    pub fn get(&self, key: &str) -> Value {
+        if let Value::Object(fields) = self {
+            fields
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.clone())
+                .unwrap_or(Value::Undefined)
+        } else {
+            Value::Undefined
+        }
+    }
+
+    pub fn set(&self, key: &str, value: Value) {
+        if let Value::Object(fields) = self {
+            let mut fields = fields.lock().unwrap();
+            if let Some(field) = fields.iter_mut().find(|(k, _)| k == key) {
+                field.1 = value;
+            } else {
+                fields.push((key.to_string(), value));
+            }
+        }
+    }
+
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Undefined | Value::Null => false,
+            Value::Bool(b) => *b,
+            Value::Number(n) => *n != 0.0 && !n.is_nan(),
+            Value::String(s) => !s.is_empty(),
+            _ => true,
+        }
+    }
+
+    pub fn to_number(&self) -> f64 {
+        match self {
+            Value::Undefined => f64::NAN,
+            Value::Null => 0.0,
+            Value::Bool(b) => *b as u8 as f64,
+            Value::Number(n) => *n,
+            Value::String(s) => {
+                let s = s.trim();
+                if s.is_empty() {
+                    0.0
+                } else if let Some(hex) = s.strip_prefix("0x") {
+                    i64::from_str_radix(hex, 16)
+                        .map(|n| n as f64)
+                        .unwrap_or(f64::NAN)
+                } else {
+                    s.parse().unwrap_or(f64::NAN)
+                }
+            }
+            _ => f64::NAN,
+        }
+    }
+
+    /// Bytes contained in a string, an array of numbers or a buffer.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Value::String(s) => s.chars().map(|c| c as u32 as u8).collect(),
+            Value::Array(values) => values
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|v| v.to_number() as i64 as u8)
+                .collect(),
+            Value::Bytes(bytes) => bytes.lock().unwrap().clone(),
+            Value::Undefined | Value::Null => Vec::new(),
+            other => other.to_js_string().chars().map(|c| c as u8).collect(),
+        }
+    }
+
+    pub fn to_js_string(&self) -> String {
+        match self {
+            Value::Undefined => "undefined".to_string(),
+            Value::Null => "null".to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Number(n) => number_to_string(*n),
+            Value::String(s) => s.clone(),
+            Value::Array(values) => values
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|v| match v {
+                    Value::Undefined | Value::Null => String::new(),
+                    v => v.to_js_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(","),
+            Value::Bytes(bytes) => bytes
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|b| b.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+            Value::Object(_) => "[object Object]".to_string(),
+            Value::Function(_) | Value::Builtin(_) => "function () { [native code] }".to_string(),
+        }
+    }
+
+    pub fn to_json(&self) -> Option<String> {
+        Some(match self {
+            Value::Undefined | Value::Function(_) | Value::Builtin(_) => return None,
+            Value::Number(n) if !n.is_finite() => "null".to_string(),
+            Value::String(s) => json_quote(s),
+            Value::Array(values) => format!(
+                "[{}]",
+                values
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|v| v.to_json().unwrap_or_else(|| "null".to_string()))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+            Value::Bytes(_) => format!("[{}]", self.to_js_string()),
+            Value::Object(fields) => format!(
+                "{{{}}}",
+                fields
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter_map(|(k, v)| v.to_json().map(|v| format!("{}:{}", json_quote(k), v)))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+            other => other.to_js_string(),
+        })
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Undefined => "undefined",
+            Value::Bool(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Function(_) | Value::Builtin(_) => "function",
+            _ => "object",
+        }
+    }
+}
+
+fn number_to_string(n: f64) -> String {
+    if n.is_nan() {
+        "NaN".to_string()
+    } else if n.is_infinite() {
+        if n > 0.0 { "Infinity" } else { "-Infinity" }.to_string()
+    } else if n.fract() == 0.0 && n.abs() < 1e21 {
+        format!("{}", n as i64)
+    } else {
+        format!("{}", n)
+    }
+}
+
+fn json_quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 || (c as u32) > 0x7e => {
+                write!(&mut quoted, "\\u{:04x}", c as u32).ok();
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Everything the interpreter cannot do by itself.
+pub trait Host {
+    /// Display some text on the console.
+    fn print(&mut self, text: &str);
+    /// Value of a global variable not defined by the script.
+    fn global(&mut self, name: &str) -> Option<Value>;
+    /// Call a builtin provided by the host.
+    fn call(&mut self, builtin: &'static str, this: &Value, args: &[Value]) -> JsResult<Value>;
+}
+
+// ------------------------------------------------------------------ lexer
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    String(String),
+    Ident(String),
+    Punct(&'static str),
+}
+
+const PUNCTUATORS: [&str; 44] = [
+    ">>>", "===", "!==", "...", "=>", "==", "!=", "<=", ">=", "&&", "||", "++", "--", "+=", "-=",
+    "*=", "/=", "|=", "&=", "<<", ">>", "(", ")", "[", "]", "{", "}", ",", ";", ".", ":", "?", "+",
+    "-", "*", "/", "%", "=", "<", ">", "!", "&", "|", "^",
+];
+
+fn tokenize(code: &str) -> JsResult<Vec<Token>> {
+    let chars: Vec<char> = code.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '/' && chars.get(i + 1) == Some(&'/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                i += 1;
+            }
+            i += 2;
+        } else if c.is_ascii_digit()
+            || (c == '.' && chars.get(i + 1).is_some_and(|c| c.is_ascii_digit()))
+        {
+            let start = i;
+            if c == '0' && matches!(chars.get(i + 1), Some('x') | Some('X')) {
+                i += 2;
+                while i < chars.len() && chars[i].is_ascii_hexdigit() {
+                    i += 1;
+                }
+                let hex: String = chars[start + 2..i].iter().collect();
+                let n = i64::from_str_radix(&hex, 16)
+                    .map_err(|_| JsError::new("SyntaxError", "Bad hex number"))?;
+                tokens.push(Token::Number(n as f64));
+                continue;
+            }
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                i += 1;
+                if i < chars.len() && (chars[i] == '-' || chars[i] == '+') {
+                    i += 1;
+                }
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+            let text: String = chars[start..i].iter().collect();
+            let n = text
+                .parse()
+                .map_err(|_| JsError::new("SyntaxError", "Bad number"))?;
+            tokens.push(Token::Number(n));
+        } else if c == '"' || c == '\'' || c == '`' {
+            let quote = c;
+            i += 1;
+            let mut s = String::new();
+            loop {
+                let c = *chars
+                    .get(i)
+                    .ok_or_else(|| JsError::new("SyntaxError", "Unterminated string"))?;
+                i += 1;
+                match c {
+                    '\\' => {
+                        let escaped = *chars
+                            .get(i)
+                            .ok_or_else(|| JsError::new("SyntaxError", "Unterminated string"))?;
+                        i += 1;
+                        match escaped {
+                            'n' => s.push('\n'),
+                            'r' => s.push('\r'),
+                            't' => s.push('\t'),
+                            '0' => s.push('\0'),
+                            'x' | 'u' => {
+                                let len = if escaped == 'x' { 2 } else { 4 };
+                                let hex: String = chars.iter().skip(i).take(len).collect();
+                                i += len;
+                                let code = u32::from_str_radix(&hex, 16)
+                                    .map_err(|_| JsError::new("SyntaxError", "Bad escape"))?;
+                                s.push(char::from_u32(code).unwrap_or('?'));
+                            }
+                            '\n' => (),
+                            other => s.push(other),
+                        }
+                    }
+                    c if c == quote => break,
+                    c => s.push(c),
+                }
+            }
+            tokens.push(Token::String(s));
+        } else if c.is_alphabetic() || c == '_' || c == '$' {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '$')
+            {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else {
+            let punct = PUNCTUATORS
+                .iter()
+                .find(|p| {
+                    p.chars()
+                        .enumerate()
+                        .all(|(j, pc)| chars.get(i + j) == Some(&pc))
+                })
+                .ok_or_else(|| JsError::new("SyntaxError", &format!("Got {c:?}")))?;
+            i += punct.len();
+            tokens.push(Token::Punct(punct));
+        }
+    }
+    Ok(tokens)
+}
+
+// ------------------------------------------------------------------ parser
+
+#[derive(Debug)]
+enum Expr {
+    Literal(Value),
+    Ident(String),
+    Array(Vec<Expr>),
+    Object(Vec<(String, Expr)>),
+    Member(Box<Expr>, String),
+    Index(Box<Expr>, Box<Expr>),
+    Call(Box<Expr>, Vec<Expr>),
+    Function(Arc<FunctionDefinition>),
+    Unary(&'static str, Box<Expr>),
+    Update(&'static str, bool, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Assign(&'static str, Box<Expr>, Box<Expr>),
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug)]
+enum Stmt {
+    Let(Vec<(String, Option<Expr>)>),
+    Expr(Expr),
+    If(Expr, Box<Stmt>, Option<Box<Stmt>>),
+    For(Option<Box<Stmt>>, Option<Expr>, Option<Expr>, Box<Stmt>),
+    While(Expr, Box<Stmt>),
+    Block(Vec<Stmt>),
+    Return(Option<Expr>),
+    Throw(Expr),
+    Function(String, Arc<FunctionDefinition>),
+    Empty,
+}
+
+#[derive(Debug)]
+struct FunctionDefinition {
+    params: Vec<String>,
+    body: Body,
+}
+
+#[derive(Debug)]
+enum Body {
+    Expr(Expr),
+    Block(Vec<Stmt>),
+}
+
+const BINARY_PRECEDENCES: [&[&str]; 10] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!=", "===", "!=="],
+    &["<", ">", "<=", ">="],
+    &["<<", ">>", ">>>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+fn syntax_error(message: &str) -> JsError {
+    JsError::new("SyntaxError", message)
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<&Token> {
+        self.tokens.get(self.position + offset)
+    }
+
+    fn next(&mut self) -> JsResult<Token> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or_else(|| syntax_error("Unexpected end of input"))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn is_punct(&self, punct: &str) -> bool {
+        matches!(self.peek(), Some(Token::Punct(p)) if *p == punct)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(i)) if i == keyword)
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        let found = self.is_punct(punct);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, punct: &str) -> JsResult<()> {
+        if self.eat(punct) {
+            Ok(())
+        } else {
+            Err(syntax_error(&format!(
+                "Got {:?} expected {punct:?}",
+                self.peek()
+            )))
+        }
+    }
+
+    fn identifier(&mut self) -> JsResult<String> {
+        match self.next()? {
+            Token::Ident(name) => Ok(name),
+            other => Err(syntax_error(&format!("Got {other:?} expected identifier"))),
+        }
+    }
+
+    fn program(&mut self) -> JsResult<Vec<Stmt>> {
+        let mut statements = Vec::new();
+        while self.peek().is_some() {
+            statements.push(self.statement()?);
+        }
+        Ok(statements)
+    }
+
+    fn block(&mut self) -> JsResult<Vec<Stmt>> {
+        self.expect("{")?;
+        let mut statements = Vec::new();
+        while !self.eat("}") {
+            statements.push(self.statement()?);
+        }
+        Ok(statements)
+    }
+
+    fn end_of_statement(&mut self) {
+        self.eat(";");
+    }
+
+    fn statement(&mut self) -> JsResult<Stmt> {
+        if self.eat(";") {
+            return Ok(Stmt::Empty);
+        }
+        if self.is_punct("{") {
+            return Ok(Stmt::Block(self.block()?));
+        }
+        let keyword = match self.peek() {
+            Some(Token::Ident(i)) => i.clone(),
+            _ => String::new(),
+        };
+        let statement = match keyword.as_str() {
+            "let" | "var" | "const" => {
+                let declarations = self.declarations()?;
+                self.end_of_statement();
+                declarations
+            }
+            "if" => {
+                self.position += 1;
+                self.expect("(")?;
+                let condition = self.expression()?;
+                self.expect(")")?;
+                let then = self.statement()?;
+                let otherwise = if self.is_keyword("else") {
+                    self.position += 1;
+                    Some(Box::new(self.statement()?))
+                } else {
+                    None
+                };
+                Stmt::If(condition, Box::new(then), otherwise)
+            }
+            "for" => {
+                self.position += 1;
+                self.expect("(")?;
+                let init = if self.eat(";") {
+                    None
+                } else {
+                    let init = if self.is_keyword("let") || self.is_keyword("var") {
+                        self.declarations()?
+                    } else {
+                        Stmt::Expr(self.expression()?)
+                    };
+                    self.expect(";")?;
+                    Some(Box::new(init))
+                };
+                let condition = if self.is_punct(";") {
+                    None
+                } else {
+                    Some(self.expression()?)
+                };
+                self.expect(";")?;
+                let update = if self.is_punct(")") {
+                    None
+                } else {
+                    Some(self.expression()?)
+                };
+                self.expect(")")?;
+                Stmt::For(init, condition, update, Box::new(self.statement()?))
+            }
+            "while" => {
+                self.position += 1;
+                self.expect("(")?;
+                let condition = self.expression()?;
+                self.expect(")")?;
+                Stmt::While(condition, Box::new(self.statement()?))
+            }
+            "return" => {
+                self.position += 1;
+                let value = if self.is_punct(";") || self.is_punct("}") || self.peek().is_none() {
+                    None
+                } else {
+                    Some(self.expression()?)
+                };
+                self.end_of_statement();
+                Stmt::Return(value)
+            }
+            "throw" => {
+                self.position += 1;
+                let value = self.expression()?;
+                self.end_of_statement();
+                Stmt::Throw(value)
+            }
+            "function" if matches!(self.peek_at(1), Some(Token::Ident(_))) => {
+                self.position += 1;
+                let name = self.identifier()?;
+                Stmt::Function(name, self.function_rest()?)
+            }
+            _ => {
+                let expression = self.expression()?;
+                self.end_of_statement();
+                Stmt::Expr(expression)
+            }
+        };
+        Ok(statement)
+    }
+
+    fn declarations(&mut self) -> JsResult<Stmt> {
+        self.position += 1;
+        let mut declarations = Vec::new();
+        loop {
+            let name = self.identifier()?;
+            let value = if self.eat("=") {
+                Some(self.assignment()?)
+            } else {
+                None
+            };
+            declarations.push((name, value));
+            if !self.eat(",") {
+                return Ok(Stmt::Let(declarations));
+            }
+        }
+    }
+
+    /// Parameters and body of a `function`.
+    fn function_rest(&mut self) -> JsResult<Arc<FunctionDefinition>> {
+        self.expect("(")?;
+        let mut params = Vec::new();
+        while !self.eat(")") {
+            params.push(self.identifier()?);
+            self.eat(",");
+        }
+        Ok(Arc::new(FunctionDefinition {
+            params,
+            body: Body::Block(self.block()?),
+        }))
+    }
+
+    fn expression(&mut self) -> JsResult<Expr> {
+        let mut expression = self.assignment()?;
+        while self.eat(",") {
+            let next = self.assignment()?;
+            expression = Expr::Binary(",", Box::new(expression), Box::new(next));
+        }
+        Ok(expression)
+    }
+
+    /// Are we looking at the parameters of an arrow function ?
+    fn arrow_ahead(&self) -> bool {
+        match self.peek() {
+            Some(Token::Ident(_)) => matches!(self.peek_at(1), Some(Token::Punct("=>"))),
+            Some(Token::Punct("(")) => {
+                let mut offset = 1;
+                loop {
+                    match self.peek_at(offset) {
+                        Some(Token::Ident(_)) | Some(Token::Punct(",")) => offset += 1,
+                        Some(Token::Punct(")")) => {
+                            return matches!(self.peek_at(offset + 1), Some(Token::Punct("=>")))
+                        }
+                        _ => return false,
+                    }
+                }
+            }
+            _ => false,
+        }
+    }
+
+    fn assignment(&mut self) -> JsResult<Expr> {
+        if self.arrow_ahead() {
+            let mut params = Vec::new();
+            if self.eat("(") {
+                while !self.eat(")") {
+                    params.push(self.identifier()?);
+                    self.eat(",");
+                }
+            } else {
+                params.push(self.identifier()?);
+            }
+            self.expect("=>")?;
+            let body = if self.is_punct("{") {
+                Body::Block(self.block()?)
+            } else {
+                Body::Expr(self.assignment()?)
+            };
+            return Ok(Expr::Function(Arc::new(FunctionDefinition {
+                params,
+                body,
+            })));
+        }
+        let target = self.conditional()?;
+        for op in ["=", "+=", "-=", "*=", "/=", "|=", "&="] {
+            if self.eat(op) {
+                let value = self.assignment()?;
+                return Ok(Expr::Assign(op, Box::new(target), Box::new(value)));
+            }
+        }
+        Ok(target)
+    }
+
+    fn conditional(&mut self) -> JsResult<Expr> {
+        let condition = self.binary(0)?;
+        if self.eat("?") {
+            let then = self.assignment()?;
+            self.expect(":")?;
+            let otherwise = self.assignment()?;
+            Ok(Expr::Conditional(
+                Box::new(condition),
+                Box::new(then),
+                Box::new(otherwise),
+            ))
+        } else {
+            Ok(condition)
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> JsResult<Expr> {
+        if level == BINARY_PRECEDENCES.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        'operators: loop {
+            for op in BINARY_PRECEDENCES[level] {
+                if self.eat(op) {
+                    let right = self.binary(level + 1)?;
+                    left = Expr::Binary(op, Box::new(left), Box::new(right));
+                    continue 'operators;
+                }
+            }
+            return Ok(left);
+        }
+    }
+
+    fn unary(&mut self) -> JsResult<Expr> {
+        for op in ["!", "-", "+", "~"] {
+            if self.eat(op) {
+                return Ok(Expr::Unary(op, Box::new(self.unary()?)));
+            }
+        }
+        if self.is_keyword("typeof") {
+            self.position += 1;
+            return Ok(Expr::Unary("typeof", Box::new(self.unary()?)));
+        }
+        for op in ["++", "--"] {
+            if self.eat(op) {
+                return Ok(Expr::Update(op, true, Box::new(self.unary()?)));
+            }
+        }
+        let expression = self.postfix()?;
+        for op in ["++", "--"] {
+            if self.eat(op) {
+                return Ok(Expr::Update(op, false, Box::new(expression)));
+            }
+        }
+        Ok(expression)
+    }
+
+    fn postfix(&mut self) -> JsResult<Expr> {
+        if self.is_keyword("new") {
+            // `new X(...)` is just a call for us
+            self.position += 1;
+        }
+        let mut expression = self.primary()?;
+        loop {
+            if self.eat(".") {
+                let name = self.identifier()?;
+                expression = Expr::Member(Box::new(expression), name);
+            } else if self.eat("[") {
+                let index = self.expression()?;
+                self.expect("]")?;
+                expression = Expr::Index(Box::new(expression), Box::new(index));
+            } else if self.eat("(") {
+                let mut args = Vec::new();
+                while !self.eat(")") {
+                    args.push(self.assignment()?);
+                    self.eat(",");
+                }
+                expression = Expr::Call(Box::new(expression), args);
+            } else {
+                return Ok(expression);
+            }
+        }
+    }
+
+    fn primary(&mut self) -> JsResult<Expr> {
+        Ok(match self.next()? {
+            Token::Number(n) => Expr::Literal(Value::Number(n)),
+            Token::String(s) => Expr::Literal(Value::String(s)),
+            Token::Ident(name) => match name.as_str() {
+                "true" => Expr::Literal(Value::Bool(true)),
+                "false" => Expr::Literal(Value::Bool(false)),
+                "null" => Expr::Literal(Value::Null),
+                "undefined" => Expr::Literal(Value::Undefined),
+                "function" => {
+                    if matches!(self.peek(), Some(Token::Ident(_))) {
+                        self.position += 1;
+                    }
+                    Expr::Function(self.function_rest()?)
+                }
+                _ => Expr::Ident(name),
+            },
+            Token::Punct("(") => {
+                let expression = self.expression()?;
+                self.expect(")")?;
+                expression
+            }
+            Token::Punct("[") => {
+                let mut values = Vec::new();
+                while !self.eat("]") {
+                    values.push(self.assignment()?);
+                    self.eat(",");
+                }
+                Expr::Array(values)
+            }
+            Token::Punct("{") => {
+                let mut fields = Vec::new();
+                while !self.eat("}") {
+                    let key = match self.next()? {
+                        Token::Ident(k) | Token::String(k) => k,
+                        Token::Number(n) => number_to_string(n),
+                        other => return Err(syntax_error(&format!("Got {other:?} expected key"))),
+                    };
+                    let value = if self.eat(":") {
+                        self.assignment()?
+                    } else {
+                        Expr::Ident(key.clone())
+                    };
+                    fields.push((key, value));
+                    self.eat(",");
+                }
+                Expr::Object(fields)
+            }
+            other => return Err(syntax_error(&format!("Got {other:?}"))),
+        })
+    }
+}
+
+// ------------------------------------------------------------------ interpreter
+
+#[derive(Debug, Default)]
+pub struct Scope {
+    variables: Mutex<HashMap<String, Value>>,
+    parent: Option<Arc<Scope>>,
+}
+
+impl Scope {
+    fn child(parent: &Arc<Scope>) -> Arc<Scope> {
+        Arc::new(Scope {
+            variables: Mutex::new(HashMap::new()),
+            parent: Some(parent.clone()),
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Option<Value> {
+        self.variables
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .or_else(|| self.parent.as_ref().and_then(|p| p.lookup(name)))
+    }
+
+    fn declare(&self, name: &str, value: Value) {
+        self.variables
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), value);
+    }
+
+    fn assign(&self, name: &str, value: Value) {
+        let mut variables = self.variables.lock().unwrap();
+        if let Some(variable) = variables.get_mut(name) {
+            *variable = value;
+        } else if let Some(parent) = &self.parent {
+            drop(variables);
+            parent.assign(name, value);
+        } else {
+            // implicit global
+            variables.insert(name.to_string(), value);
+        }
+    }
+}
+
+enum Flow {
+    Normal,
+    Return(Value),
+}
+
+/// Javascript global state, kept from one executed line to the next.
+#[derive(Default)]
+pub struct Interpreter {
+    globals: Arc<Scope>,
+}
+
+impl Interpreter {
+    /// Parse and run given code, returning the value of the last expression statement.
+    pub fn execute(&mut self, code: &str, host: &mut dyn Host) -> JsResult<Value> {
+        let tokens = tokenize(code)?;
+        let program = Parser {
+            tokens,
+            position: 0,
+        }
+        .program()?;
+        let mut last = Value::Undefined;
+        let globals = self.globals.clone();
+        for statement in &program {
+            if let Stmt::Expr(expression) = statement {
+                last = self.evaluate(expression, &globals, host)?;
+            } else {
+                last = Value::Undefined;
+                if let Flow::Return(_) = self.run(statement, &globals, host)? {
+                    break;
+                }
+            }
+        }
+        Ok(last)
+    }
+
+    fn run(&mut self, statement: &Stmt, scope: &Arc<Scope>, host: &mut dyn Host) -> JsResult<Flow> {
+        match statement {
+            Stmt::Let(declarations) => {
+                for (name, value) in declarations {
+                    let value = match value {
+                        Some(v) => self.evaluate(v, scope, host)?,
+                        None => Value::Undefined,
+                    };
+                    scope.declare(name, value);
+                }
+            }
+            Stmt::Expr(expression) => {
+                self.evaluate(expression, scope, host)?;
+            }
+            Stmt::If(condition, then, otherwise) => {
+                if self.evaluate(condition, scope, host)?.is_truthy() {
+                    return self.run(then, scope, host);
+                } else if let Some(otherwise) = otherwise {
+                    return self.run(otherwise, scope, host);
+                }
+            }
+            Stmt::For(init, condition, update, body) => {
+                if let Some(init) = init {
+                    self.run(init, scope, host)?;
+                }
+                while match condition {
+                    Some(c) => self.evaluate(c, scope, host)?.is_truthy(),
+                    None => true,
+                } {
+                    if let Flow::Return(v) = self.run(body, scope, host)? {
+                        return Ok(Flow::Return(v));
+                    }
+                    if let Some(update) = update {
+                        self.evaluate(update, scope, host)?;
+                    }
+                }
+            }
+            Stmt::While(condition, body) => {
+                while self.evaluate(condition, scope, host)?.is_truthy() {
+                    if let Flow::Return(v) = self.run(body, scope, host)? {
+                        return Ok(Flow::Return(v));
+                    }
+                }
+            }
+            Stmt::Block(statements) => {
+                for statement in statements {
+                    if let Flow::Return(v) = self.run(statement, scope, host)? {
+                        return Ok(Flow::Return(v));
+                    }
+                }
+            }
+            Stmt::Return(value) => {
+                let value = match value {
+                    Some(v) => self.evaluate(v, scope, host)?,
+                    None => Value::Undefined,
+                };
+                return Ok(Flow::Return(value));
+            }
+            Stmt::Throw(value) => return Err(JsError(self.evaluate(value, scope, host)?)),
+            Stmt::Function(name, definition) => scope.declare(
+                name,
+                Value::Function(Arc::new(Function {
+                    definition: definition.clone(),
+                    scope: scope.clone(),
+                })),
+            ),
+            Stmt::Empty => (),
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn evaluate(
+        &mut self,
+        expression: &Expr,
+        scope: &Arc<Scope>,
+        host: &mut dyn Host,
+    ) -> JsResult<Value> {
+        Ok(match expression {
+            Expr::Literal(v) => v.clone(),
+            Expr::Ident(name) => match scope.lookup(name) {
+                Some(v) => v,
+                None => global(name).or_else(|| host.global(name)).ok_or_else(|| {
+                    JsError::new("ReferenceError", &format!("{name:?} is not defined"))
+                })?,
+            },
+            Expr::Array(values) => Value::array(
+                values
+                    .iter()
+                    .map(|v| self.evaluate(v, scope, host))
+                    .collect::<JsResult<_>>()?,
+            ),
+            Expr::Object(fields) => {
+                let mut values = Vec::new();
+                for (key, value) in fields {
+                    values.push((key.clone(), self.evaluate(value, scope, host)?));
+                }
+                Value::Object(Arc::new(Mutex::new(values)))
+            }
+            Expr::Member(object, name) => {
+                let object = self.evaluate(object, scope, host)?;
+                member(&object, name)?
+            }
+            Expr::Index(object, index) => {
+                let object = self.evaluate(object, scope, host)?;
+                let index = self.evaluate(index, scope, host)?;
+                member(&object, &index.to_js_string())?
+            }
+            Expr::Call(callee, args) => {
+                let (this, function) = match callee.as_ref() {
+                    Expr::Member(object, name) => {
+                        let this = self.evaluate(object, scope, host)?;
+                        let function = member(&this, name)?;
+                        (this, function)
+                    }
+                    callee => (Value::Undefined, self.evaluate(callee, scope, host)?),
+                };
+                let args = args
+                    .iter()
+                    .map(|a| self.evaluate(a, scope, host))
+                    .collect::<JsResult<Vec<_>>>()?;
+                self.call(&function, &this, &args, host)?
+            }
+            Expr::Function(definition) => Value::Function(Arc::new(Function {
+                definition: definition.clone(),
+                scope: scope.clone(),
+            })),
+            Expr::Unary(op, operand) => {
+                let value = self.evaluate(operand, scope, host)?;
+                match *op {
+                    "!" => Value::Bool(!value.is_truthy()),
+                    "-" => Value::Number(-value.to_number()),
+                    "+" => Value::Number(value.to_number()),
+                    "~" => Value::Number(!(value.to_number() as i64 as i32) as f64),
+                    _ => Value::from(value.type_name()),
+                }
+            }
+            Expr::Update(op, prefix, target) => {
+                let old = self.evaluate(target, scope, host)?.to_number();
+                let new = if *op == "++" { old + 1.0 } else { old - 1.0 };
+                self.store(target, Value::Number(new), scope, host)?;
+                Value::Number(if *prefix { new } else { old })
+            }
+            Expr::Binary("&&", left, right) => {
+                let left = self.evaluate(left, scope, host)?;
+                if left.is_truthy() {
+                    self.evaluate(right, scope, host)?
+                } else {
+                    left
+                }
+            }
+            Expr::Binary("||", left, right) => {
+                let left = self.evaluate(left, scope, host)?;
+                if left.is_truthy() {
+                    left
+                } else {
+                    self.evaluate(right, scope, host)?
+                }
+            }
+            Expr::Binary(op, left, right) => {
+                let left = self.evaluate(left, scope, host)?;
+                let right = self.evaluate(right, scope, host)?;
+                binary(op, &left, &right)
+            }
+            Expr::Assign(op, target, value) => {
+                let mut value = self.evaluate(value, scope, host)?;
+                if *op != "=" {
+                    let old = self.evaluate(target, scope, host)?;
+                    value = binary(&op[..op.len() - 1], &old, &value);
+                }
+                self.store(target, value.clone(), scope, host)?;
+                value
+            }
+            Expr::Conditional(condition, then, otherwise) => {
+                if self.evaluate(condition, scope, host)?.is_truthy() {
+                    self.evaluate(then, scope, host)?
+                } else {
+                    self.evaluate(otherwise, scope, host)?
+                }
+            }
+        })
+    }
+
+    fn store(
+        &mut self,
+        target: &Expr,
+        value: Value,
+        scope: &Arc<Scope>,
+        host: &mut dyn Host,
+    ) -> JsResult<()> {
+        match target {
+            Expr::Ident(name) => scope.assign(name, value),
+            Expr::Member(object, name) => {
+                let object = self.evaluate(object, scope, host)?;
+                set_member(&object, name, value)?;
+            }
+            Expr::Index(object, index) => {
+                let object = self.evaluate(object, scope, host)?;
+                let index = self.evaluate(index, scope, host)?;
+                set_member(&object, &index.to_js_string(), value)?;
+            }
+            _ => return Err(syntax_error("Invalid assignment target")),
+        }
+        Ok(())
+    }
+
+    /// Call given function value.
+    pub fn call(
+        &mut self,
+        function: &Value,
+        this: &Value,
+        args: &[Value],
+        host: &mut dyn Host,
+    ) -> JsResult<Value> {
+        match function {
+            Value::Function(function) => {
+                let scope = Scope::child(&function.scope);
+                for (i, param) in function.definition.params.iter().enumerate() {
+                    scope.declare(param, args.get(i).cloned().unwrap_or(Value::Undefined));
+                }
+                match &function.definition.body {
+                    Body::Expr(expression) => self.evaluate(expression, &scope, host),
+                    Body::Block(statements) => {
+                        for statement in statements {
+                            if let Flow::Return(v) = self.run(statement, &scope, host)? {
+                                return Ok(v);
+                            }
+                        }
+                        Ok(Value::Undefined)
+                    }
+                }
+            }
+            Value::Builtin(name) => self.builtin(name, this, args, host),
+            other => Err(JsError::new(
+                "TypeError",
+                &format!("{} is not a function", other.to_js_string()),
+            )),
+        }
+    }
+
+    fn builtin(
+        &mut self,
+        name: &'static str,
+        this: &Value,
+        args: &[Value],
+        host: &mut dyn Host,
+    ) -> JsResult<Value> {
+        let arg = |i: usize| args.get(i).cloned().unwrap_or(Value::Undefined);
+        let length = |v: &Value| match v {
+            Value::Undefined => None,
+            v => Some(v.to_number().max(0.0) as usize),
+        };
+        Ok(match name {
+            "console.log" => {
+                let text = args
+                    .iter()
+                    .map(|a| a.to_js_string())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                host.print(&format!("{text}\r\n"));
+                Value::Undefined
+            }
+            "JSON.stringify" => arg(0)
+                .to_json()
+                .map(Value::String)
+                .unwrap_or(Value::Undefined),
+            "Error" => JsError::new("Error", &arg(0).to_js_string()).0,
+            "String.fromCharCode" => Value::String(
+                args.iter()
+                    .map(|a| char::from_u32(a.to_number() as u32).unwrap_or('?'))
+                    .collect(),
+            ),
+            "btoa" => Value::String(BASE64_STANDARD.encode(arg(0).to_bytes())),
+            "atob" => {
+                let bytes = BASE64_STANDARD
+                    .decode(arg(0).to_js_string())
+                    .map_err(|_| JsError::new("Error", "Invalid base64"))?;
+                Value::String(bytes.iter().map(|&b| b as char).collect())
+            }
+            "Uint8Array" => {
+                let mut bytes = match arg(0) {
+                    Value::Number(n) => vec![0; n as usize],
+                    v => v.to_bytes(),
+                };
+                let offset = length(&arg(1)).unwrap_or(0).min(bytes.len());
+                bytes.drain(..offset);
+                if let Some(len) = length(&arg(2)) {
+                    bytes.truncate(len);
+                }
+                Value::bytes(bytes)
+            }
+            "forEach" | "map" | "filter" => {
+                let values = elements(this);
+                let mut results = Vec::new();
+                for (i, value) in values.into_iter().enumerate() {
+                    let result = self.call(
+                        &arg(0),
+                        &Value::Undefined,
+                        &[value.clone(), Value::Number(i as f64)],
+                        host,
+                    )?;
+                    match name {
+                        "map" => results.push(result),
+                        "filter" if result.is_truthy() => results.push(value),
+                        _ => (),
+                    }
+                }
+                if name == "forEach" {
+                    Value::Undefined
+                } else {
+                    Value::array(results)
+                }
+            }
+            "push" => {
+                if let Value::Array(values) = this {
+                    let mut values = values.lock().unwrap();
+                    values.extend(args.iter().cloned());
+                    Value::Number(values.len() as f64)
+                } else {
+                    Value::Undefined
+                }
+            }
+            "join" => {
+                let separator = match arg(0) {
+                    Value::Undefined => ",".to_string(),
+                    s => s.to_js_string(),
+                };
+                Value::String(
+                    elements(this)
+                        .iter()
+                        .map(|v| v.to_js_string())
+                        .collect::<Vec<_>>()
+                        .join(&separator),
+                )
+            }
+            "indexOf" => {
+                let position = match this {
+                    Value::String(s) => {
+                        let chars: Vec<char> = s.chars().collect();
+                        let needle: Vec<char> = arg(0).to_js_string().chars().collect();
+                        (0..=chars.len().saturating_sub(needle.len()))
+                            .find(|&i| chars[i..].starts_with(&needle))
+                            .filter(|_| needle.len() <= chars.len())
+                    }
+                    v => elements(v).iter().position(|e| strict_equals(e, &arg(0))),
+                };
+                Value::Number(position.map(|p| p as f64).unwrap_or(-1.0))
+            }
+            "slice" | "substring" | "substr" => {
+                let chars: Vec<Value> = elements(this);
+                let len = chars.len() as f64;
+                let index = |v: Value, default: f64| {
+                    let n = if let Value::Undefined = v {
+                        default
+                    } else {
+                        v.to_number()
+                    };
+                    (if n < 0.0 {
+                        (len + n).max(0.0)
+                    } else {
+                        n.min(len)
+                    }) as usize
+                };
+                let start = index(arg(0), 0.0);
+                let end = if name == "substr" {
+                    match length(&arg(1)) {
+                        Some(l) => (start + l).min(chars.len()),
+                        None => chars.len(),
+                    }
+                } else {
+                    index(arg(1), len).max(start)
+                };
+                let selected = chars[start..end].to_vec();
+                match this {
+                    Value::String(_) => {
+                        Value::String(selected.iter().map(|c| c.to_js_string()).collect())
+                    }
+                    Value::Bytes(_) => {
+                        Value::bytes(selected.iter().map(|v| v.to_number() as u8).collect())
+                    }
+                    _ => Value::array(selected),
+                }
+            }
+            "charCodeAt" => {
+                let index = length(&arg(0)).unwrap_or(0);
+                this.to_js_string()
+                    .chars()
+                    .nth(index)
+                    .map(|c| Value::Number(c as u32 as f64))
+                    .unwrap_or(Value::Number(f64::NAN))
+            }
+            "split" => {
+                let s = this.to_js_string();
+                let separator = arg(0).to_js_string();
+                let parts = if separator.is_empty() {
+                    s.chars().map(|c| Value::String(c.to_string())).collect()
+                } else {
+                    s.split(separator.as_str()).map(Value::from).collect()
+                };
+                Value::array(parts)
+            }
+            "startsWith" => Value::Bool(this.to_js_string().starts_with(&arg(0).to_js_string())),
+            "endsWith" => Value::Bool(this.to_js_string().ends_with(&arg(0).to_js_string())),
+            "toString" => Value::String(this.to_js_string()),
+            _ => host.call(name, this, args)?,
+        })
+    }
+}
+
+/// Globals every javascript has.
+fn global(name: &str) -> Option<Value> {
+    Some(match name {
+        "console" => Value::object(vec![("log", Value::Builtin("console.log"))]),
+        "JSON" => Value::object(vec![("stringify", Value::Builtin("JSON.stringify"))]),
+        "String" => Value::object(vec![(
+            "fromCharCode",
+            Value::Builtin("String.fromCharCode"),
+        )]),
+        "Error" => Value::Builtin("Error"),
+        "Uint8Array" => Value::Builtin("Uint8Array"),
+        "btoa" => Value::Builtin("btoa"),
+        "atob" => Value::Builtin("atob"),
+        _ => return None,
+    })
+}
+
+/// Elements of something we can iterate on.
+fn elements(value: &Value) -> Vec<Value> {
+    match value {
+        Value::Array(values) => values.lock().unwrap().clone(),
+        Value::Bytes(bytes) => bytes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|&b| Value::Number(b as f64))
+            .collect(),
+        Value::String(s) => s.chars().map(|c| Value::String(c.to_string())).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn member(object: &Value, name: &str) -> JsResult<Value> {
+    const METHODS: [&str; 15] = [
+        "forEach",
+        "map",
+        "filter",
+        "push",
+        "join",
+        "indexOf",
+        "slice",
+        "substring",
+        "substr",
+        "charCodeAt",
+        "split",
+        "startsWith",
+        "endsWith",
+        "toString",
+        "buffer",
+    ];
+    Ok(match object {
+        Value::Undefined | Value::Null => {
+            return Err(JsError::new(
+                "TypeError",
+                &format!("Cannot read property {name:?} of {}", object.to_js_string()),
+            ))
+        }
+        Value::Object(_) => object.get(name),
+        Value::String(_) | Value::Array(_) | Value::Bytes(_) => {
+            let values = elements(object);
+            if name == "length" || name == "byteLength" {
+                Value::Number(values.len() as f64)
+            } else if name == "buffer" {
+                object.clone()
+            } else if let Some(method) = METHODS.iter().find(|m| **m == name) {
+                Value::Builtin(method)
+            } else if let Ok(index) = name.parse::<usize>() {
+                values.get(index).cloned().unwrap_or(Value::Undefined)
+            } else {
+                Value::Undefined
+            }
+        }
+        _ => Value::Undefined,
+    })
+}
+
+fn set_member(object: &Value, name: &str, value: Value) -> JsResult<()> {
+    match object {
+        Value::Object(_) => object.set(name, value),
+        Value::Array(values) => {
+            if let Ok(index) = name.parse::<usize>() {
+                let mut values = values.lock().unwrap();
+                if values.len() <= index {
+                    values.resize(index + 1, Value::Undefined);
+                }
+                values[index] = value;
+            }
+        }
+        Value::Bytes(bytes) => {
+            if let Ok(index) = name.parse::<usize>() {
+                if let Some(b) = bytes.lock().unwrap().get_mut(index) {
+                    *b = value.to_number() as i64 as u8;
+                }
+            }
+        }
+        other => {
+            return Err(JsError::new(
+                "TypeError",
+                &format!("Cannot set property {name:?} of {}", other.to_js_string()),
+            ))
+        }
+    }
+    Ok(())
+}
+
+fn strict_equals(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Undefined, Value::Undefined) | (Value::Null, Value::Null) => true,
+        (Value::Bool(a), Value::Bool(b)) => a == b,
+        (Value::Number(a), Value::Number(b)) => a == b,
+        (Value::String(a), Value::String(b)) => a == b,
+        (Value::Array(a), Value::Array(b)) => Arc::ptr_eq(a, b),
+        (Value::Object(a), Value::Object(b)) => Arc::ptr_eq(a, b),
+        (Value::Bytes(a), Value::Bytes(b)) => Arc::ptr_eq(a, b),
+        (Value::Builtin(a), Value::Builtin(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn loose_equals(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Undefined | Value::Null, Value::Undefined | Value::Null) => true,
+        (Value::Undefined | Value::Null, _) | (_, Value::Undefined | Value::Null) => false,
+        (Value::Number(_), Value::String(_)) | (Value::String(_), Value::Number(_)) => {
+            left.to_number() == right.to_number()
+        }
+        (Value::Bool(_), _) | (_, Value::Bool(_)) => left.to_number() == right.to_number(),
+        _ => strict_equals(left, right),
+    }
+}
+
+fn binary(op: &str, left: &Value, right: &Value) -> Value {
+    let (a, b) = (left.to_number(), right.to_number());
+    let int = |n: f64| n as i64 as i32;
+    match op {
+        "+" => {
+            let is_text = |v: &Value| {
+                !matches!(
+                    v,
+                    Value::Undefined | Value::Null | Value::Bool(_) | Value::Number(_)
+                )
+            };
+            if is_text(left) || is_text(right) {
+                Value::String(left.to_js_string() + &right.to_js_string())
+            } else {
+                Value::Number(a + b)
+            }
+        }
+        "-" => Value::Number(a - b),
+        "*" => Value::Number(a * b),
+        "/" => Value::Number(a / b),
+        "%" => Value::Number(a % b),
+        "|" => Value::Number((int(a) | int(b)) as f64),
+        "&" => Value::Number((int(a) & int(b)) as f64),
+        "^" => Value::Number((int(a) ^ int(b)) as f64),
+        "<<" => Value::Number((int(a) << (int(b) & 31)) as f64),
+        ">>" => Value::Number((int(a) >> (int(b) & 31)) as f64),
+        ">>>" => Value::Number(((int(a) as u32) >> (int(b) & 31)) as f64),
+        "==" => Value::Bool(loose_equals(left, right)),
+        "!=" => Value::Bool(!loose_equals(left, right)),
+        "===" => Value::Bool(strict_equals(left, right)),
+        "!==" => Value::Bool(!strict_equals(left, right)),
+        "<" | ">" | "<=" | ">=" => {
+            let ordering = match (left, right) {
+                (Value::String(x), Value::String(y)) => x.partial_cmp(y),
+                _ => a.partial_cmp(&b),
+            };
+            Value::Bool(match ordering {
+                None => false,
+                Some(o) => match op {
+                    "<" => o.is_lt(),
+                    ">" => o.is_gt(),
+                    "<=" => o.is_le(),
+                    _ => o.is_ge(),
+                },
+            })
+        }
+        // ","
+        _ => right.clone(),
+    }
+}
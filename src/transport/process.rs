@@ -0,0 +1,57 @@
+use super::{
+    stream::{StreamTransport, EMULATOR_CHUNK_SIZE},
+    Transport,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use futures_util::stream::BoxStream;
+use std::process::Stdio;
+use tokio::process::{Child, Command};
+use tokio::sync::Mutex;
+
+/// Talk to a locally spawned espruino (linux build, emulator) through its stdin/stdout.
+pub struct ProcessTransport {
+    stream: StreamTransport,
+    child: Mutex<Child>,
+}
+
+impl ProcessTransport {
+    /// Spawn given shell command line.
+    pub fn spawn(command_line: &str) -> Result<Self> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command_line)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| anyhow::anyhow!("unable to spawn {command_line}: {e}"))?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        Ok(ProcessTransport {
+            stream: StreamTransport::new(stdout, stdin, EMULATOR_CHUNK_SIZE),
+            child: Mutex::new(child),
+        })
+    }
+}
+
+#[async_trait]
+impl Transport for ProcessTransport {
+    fn max_write_len(&self) -> Result<usize> {
+        self.stream.max_write_len()
+    }
+
+    async fn write(&self, bytes: &[u8]) -> Result<()> {
+        self.stream.write(bytes).await
+    }
+
+    async fn notifications(&self) -> Result<BoxStream<'_, Result<Vec<u8>>>> {
+        self.stream.notifications().await
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.stream.disconnect().await?;
+        self.child.lock().await.kill().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,16 @@
+use super::stream::StreamTransport;
+use anyhow::Result;
+use tokio_serial::SerialPortBuilderExt;
+
+/// Espruino boards only have small input buffers, keep writes short
+/// so that XOFF is honored quickly.
+const SERIAL_CHUNK_SIZE: usize = 64;
+
+/// Open given serial device (`/dev/ttyACM0`, `/dev/ttyUSB0`, a pty...) at given baud rate.
+pub fn open(device: &str, baud_rate: u32) -> Result<StreamTransport> {
+    let port = tokio_serial::new(device, baud_rate)
+        .open_native_async()
+        .map_err(|e| anyhow::anyhow!("unable to open {device}: {e}"))?;
+    let (reader, writer) = tokio::io::split(port);
+    Ok(StreamTransport::new(reader, writer, SERIAL_CHUNK_SIZE))
+}
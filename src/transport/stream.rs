@@ -0,0 +1,74 @@
+use super::Transport;
+use anyhow::Result;
+use async_trait::async_trait;
+use futures_util::{stream::BoxStream, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+/// Emulators have no real input buffer limit, we can send big chunks.
+pub const EMULATOR_CHUNK_SIZE: usize = 1024;
+
+type Reader = Box<dyn AsyncRead + Send + Unpin>;
+type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Transport over any pair of byte streams (serial port, tcp socket, pipes...).
+pub struct StreamTransport {
+    reader: Mutex<Option<Reader>>,
+    writer: Mutex<Writer>,
+    chunk_size: usize,
+}
+
+impl StreamTransport {
+    /// Wrap given streams, writes will be split into chunks of at most `chunk_size` bytes.
+    pub fn new(
+        reader: impl AsyncRead + Send + Unpin + 'static,
+        writer: impl AsyncWrite + Send + Unpin + 'static,
+        chunk_size: usize,
+    ) -> Self {
+        StreamTransport {
+            reader: Mutex::new(Some(Box::new(reader))),
+            writer: Mutex::new(Box::new(writer)),
+            chunk_size,
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for StreamTransport {
+    fn max_write_len(&self) -> Result<usize> {
+        Ok(self.chunk_size)
+    }
+
+    async fn write(&self, bytes: &[u8]) -> Result<()> {
+        let mut writer = self.writer.lock().await;
+        writer.write_all(bytes).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    async fn notifications(&self) -> Result<BoxStream<'_, Result<Vec<u8>>>> {
+        let reader = self
+            .reader
+            .lock()
+            .await
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("stream is already being read"))?;
+        let stream = futures_util::stream::unfold(reader, |mut reader| async move {
+            let mut buffer = vec![0; 1024];
+            match reader.read(&mut buffer).await {
+                Ok(0) => None,
+                Ok(n) => {
+                    buffer.truncate(n);
+                    Some((Ok(buffer), reader))
+                }
+                Err(e) => Some((Err(e.into()), reader)),
+            }
+        });
+        Ok(stream.boxed())
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.writer.lock().await.shutdown().await?;
+        Ok(())
+    }
+}
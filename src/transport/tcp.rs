@@ -0,0 +1,14 @@
+use super::stream::{StreamTransport, EMULATOR_CHUNK_SIZE};
+use anyhow::Result;
+use tokio::net::TcpStream;
+
+/// Connect to an espruino listening on given `host:port`
+/// (for example the linux build started with `--telnet`).
+pub async fn connect(address: &str) -> Result<StreamTransport> {
+    let stream = TcpStream::connect(address)
+        .await
+        .map_err(|e| anyhow::anyhow!("unable to connect to {address}: {e}"))?;
+    stream.set_nodelay(true)?;
+    let (reader, writer) = stream.into_split();
+    Ok(StreamTransport::new(reader, writer, EMULATOR_CHUNK_SIZE))
+}
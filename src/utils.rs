@@ -1,7 +1,16 @@
 use anyhow::Result;
 use tokio::{fs::File, io::AsyncReadExt, io::AsyncWriteExt};
 
+/// Name standing for stdin or stdout.
+pub const STDIO: &str = "-";
+
 pub async fn save_file(filename: &str, file_content: &[u8]) -> Result<()> {
+    if filename == STDIO {
+        let mut stdout = tokio::io::stdout();
+        stdout.write_all(file_content).await?;
+        stdout.flush().await?;
+        return Ok(());
+    }
     let f = File::create(filename).await?;
     let mut writer = tokio::io::BufWriter::new(f);
     writer.write_all(file_content).await?;
@@ -10,9 +19,13 @@ pub async fn save_file(filename: &str, file_content: &[u8]) -> Result<()> {
 }
 
 pub async fn read_file(filename: &str) -> Result<Vec<u8>> {
+    let mut content = Vec::new();
+    if filename == STDIO {
+        tokio::io::stdin().read_to_end(&mut content).await?;
+        return Ok(content);
+    }
     let f = File::open(filename).await?;
     let mut r = tokio::io::BufReader::new(f);
-    let mut content = Vec::new();
     r.read_to_end(&mut content).await?;
     Ok(content)
 }
@@ -0,0 +1,518 @@
+use banglecomm::archive::{self, Selection};
+use banglecomm::network::{JsException, Timeout};
+use banglecomm::transport::fake::FakeWatch;
+use banglecomm::transport::stream::StreamTransport;
+use banglecomm::{Bangle, CalendarEvent, FileInfo, FileKind, Progress};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+fn connect(watch: &FakeWatch) -> Bangle {
+    Bangle::new(Box::new(watch.clone()))
+}
+
+#[tokio::test]
+async fn clock_is_set() {
+    let watch = FakeWatch::new();
+    let bangle = connect(&watch);
+    bangle.set_time(1_700_000_000).await.unwrap();
+    assert_eq!(watch.time(), Some(1_700_000_000.0));
+}
+
+#[tokio::test]
+async fn upload_is_sent_in_chunks_with_flow_control() {
+    let watch = FakeWatch::new().with_flow_control(200);
+    let bangle = connect(&watch);
+    let content: Vec<u8> = (0..3000).map(|i| (i * 7 % 256) as u8).collect();
+    bangle.write_file("big.bin", &content).await.unwrap();
+    assert_eq!(watch.file("big.bin").unwrap(), content);
+    assert_eq!(watch.overrun(), 0);
+}
+
+#[tokio::test]
+async fn download_is_reassembled_from_small_packets() {
+    let watch = FakeWatch::new().with_packet_size(3);
+    let bangle = connect(&watch);
+    let content: Vec<u8> = (0..=255).collect();
+    watch.set_file("all.bin", &content);
+    assert_eq!(bangle.read_file("all.bin").await.unwrap(), content);
+}
+
+#[tokio::test]
+async fn files_are_listed_and_erased() {
+    let watch = FakeWatch::new();
+    watch.set_file("a.txt", b"a");
+    watch.set_file("b.txt", b"b");
+    let bangle = connect(&watch);
+    bangle.erase("a.txt").await.unwrap();
+    assert_eq!(bangle.list_files().await.unwrap(), vec!["b.txt"]);
+}
+
+#[tokio::test]
+async fn expressions_are_evaluated() {
+    let bangle = connect(&FakeWatch::new());
+    assert_eq!(
+        bangle.eval("[1, 2].map(x => x * 2)").await.unwrap(),
+        "[2,4]"
+    );
+}
+
+#[tokio::test]
+async fn console_output_is_forwarded() {
+    let bangle = connect(&FakeWatch::new());
+    let mut console = bangle.console();
+    bangle
+        .run("let x = 3;\nconsole.log('x is', x);")
+        .await
+        .unwrap();
+    assert_eq!(console.recv().await.unwrap(), "x is 3");
+}
+
+#[tokio::test]
+async fn calendar_events_are_stored() {
+    let watch = FakeWatch::new();
+    let bangle = connect(&watch);
+    let events = [
+        CalendarEvent {
+            title: "party".to_string(),
+            description: Some("home".to_string()),
+            timestamp: 4070944800,
+        },
+        CalendarEvent {
+            title: "work".to_string(),
+            description: None,
+            timestamp: 4070948400,
+        },
+    ];
+    bangle.set_calendar(&events).await.unwrap();
+    let stored = watch.file("android.calendar.json").unwrap();
+    assert_eq!(
+        String::from_utf8(stored).unwrap(),
+        "[{\"title\":\"party\",\"description\":\"home\",\"timestamp\":4070944800},\
+{\"title\":\"work\",\"timestamp\":4070948400}]"
+    );
+}
+
+#[tokio::test]
+async fn concurrent_requests_get_their_own_responses() {
+    let watch = FakeWatch::new().with_packet_size(7);
+    watch.set_file("a.bin", &[1, 2, 3]);
+    watch.set_file("b.bin", &[4, 5]);
+    let bangle = connect(&watch);
+    let (a, b, files, value) = tokio::join!(
+        bangle.read_file("a.bin"),
+        bangle.read_file("b.bin"),
+        bangle.list_files(),
+        bangle.eval("1 + 1"),
+    );
+    assert_eq!(a.unwrap(), vec![1, 2, 3]);
+    assert_eq!(b.unwrap(), vec![4, 5]);
+    assert_eq!(files.unwrap(), vec!["a.bin", "b.bin"]);
+    assert_eq!(value.unwrap(), "2");
+}
+
+#[tokio::test]
+async fn silent_watch_times_out_then_recovers() {
+    let watch = FakeWatch::new();
+    let bangle = connect(&watch).with_timeout(Duration::from_millis(100));
+    watch.set_responsive(false);
+    let error = bangle.eval("1").await.unwrap_err();
+    assert!(error.downcast_ref::<Timeout>().is_some());
+    watch.set_responsive(true);
+    assert_eq!(bangle.eval("2").await.unwrap(), "2");
+}
+
+#[tokio::test]
+async fn exceptions_fail_the_command() {
+    let watch = FakeWatch::new();
+    let bangle = connect(&watch);
+    let mut console = bangle.console();
+    let error = bangle.run("throw new Error('boom')").await.unwrap_err();
+    let exception = error.downcast_ref::<JsException>().unwrap();
+    assert_eq!(exception.message, "Uncaught Error: boom");
+    assert_eq!(exception.trace, vec![" at line 1 col 1"]);
+    assert!(bangle.read_file("missing.txt").await.is_err());
+    // the trace is not displayed on the console and we can go on
+    bangle.run("console.log('ok')").await.unwrap();
+    assert_eq!(console.recv().await.unwrap(), "ok");
+}
+
+#[tokio::test]
+async fn lost_link_fails_running_command_then_reconnects() {
+    let watch = FakeWatch::new();
+    watch.set_file("a.bin", &[1, 2, 3]);
+    let bangle = connect(&watch);
+    assert_eq!(bangle.eval("1").await.unwrap(), "1");
+    // the watch hangs then goes out of range in the middle of a command
+    watch.set_responsive(false);
+    let (result, ()) = tokio::join!(bangle.eval("2"), async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        watch.drop_link();
+    });
+    let error = result.unwrap_err();
+    assert!(error.downcast_ref::<Timeout>().is_none());
+    watch.set_responsive(true);
+    assert_eq!(bangle.read_file("a.bin").await.unwrap(), vec![1, 2, 3]);
+}
+
+#[tokio::test]
+async fn binary_download_goes_through_base64() {
+    let watch = FakeWatch::new();
+    let content: Vec<u8> = (0..2000).map(|i| (i * 7 % 256) as u8).collect();
+    watch.set_file("track.bin", &content);
+    watch.set_file("empty", &[]);
+    let bangle = connect(&watch);
+    assert_eq!(bangle.read_file("track.bin").await.unwrap(), content);
+    assert!(bangle.read_file("empty").await.unwrap().is_empty());
+    let error = bangle.read_file("missing").await.unwrap_err();
+    assert_eq!(error.to_string(), "Uncaught Error: File not found");
+}
+
+#[tokio::test]
+async fn uploads_mix_plain_and_compressed_chunks() {
+    let watch = FakeWatch::new();
+    let bangle = connect(&watch);
+    // a compressible half then a random one
+    let mut seed = 42u32;
+    let content: Vec<u8> = b"let x = 1;\n"
+        .iter()
+        .copied()
+        .cycle()
+        .take(1500)
+        .chain((0..1500).map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        }))
+        .collect();
+    bangle.write_file("mixed.bin", &content).await.unwrap();
+    assert_eq!(watch.file("mixed.bin").unwrap(), content);
+    bangle.write_file("tiny", b"\x00\xff").await.unwrap();
+    assert_eq!(watch.file("tiny").unwrap(), b"\x00\xff");
+}
+
+#[tokio::test]
+async fn corrupted_upload_chunks_are_sent_again() {
+    let watch = FakeWatch::new();
+    let bangle = connect(&watch);
+    let content: Vec<u8> = (0..3000).map(|i| (i * 13 % 251) as u8).collect();
+    watch.corrupt_writes(2);
+    bangle.write_file("big.bin", &content).await.unwrap();
+    assert_eq!(watch.file("big.bin").unwrap(), content);
+    // flash can't be written over, damage past the first chunk needs a new file too
+    watch.corrupt_writes_after(1, 1);
+    bangle.write_file("big.bin", &content).await.unwrap();
+    assert_eq!(watch.file("big.bin").unwrap(), content);
+    // a watch always damaging what it receives can't be fixed
+    watch.corrupt_writes(100);
+    let error = bangle.write_file("big.bin", &content).await.unwrap_err();
+    assert!(error.to_string().contains("still corrupted"));
+    assert_eq!(
+        bangle.read_file("big.bin").await.unwrap().len(),
+        content.len()
+    );
+}
+
+#[tokio::test]
+async fn verification_can_be_disabled() {
+    let watch = FakeWatch::new();
+    let bangle = connect(&watch).with_verification(false);
+    watch.corrupt_writes(1);
+    bangle.write_file("a.bin", &[1, 2, 3]).await.unwrap();
+    assert_eq!(watch.file("a.bin").unwrap(), vec![0xfe, 2, 3]);
+}
+
+#[tokio::test]
+async fn first_lines_are_read_without_the_rest() {
+    let watch = FakeWatch::new();
+    let log: Vec<u8> = (0..5000).map(|i| b"12,34\n"[i % 6]).collect();
+    watch.set_file("log.csv", &log);
+    watch.set_storage_file("log", &log);
+    let bangle = connect(&watch);
+    for name in ["log.csv", "log"] {
+        // 128 lines per chunk
+        let start = bangle.read_lines(name, 200).await.unwrap();
+        assert_eq!(start, log[..1536]);
+        assert!(bangle.read_lines(name, 0).await.unwrap().is_empty());
+        assert_eq!(bangle.read_lines(name, 1000).await.unwrap(), log);
+    }
+}
+
+#[tokio::test]
+async fn damaged_replies_are_asked_again() {
+    let watch = FakeWatch::new();
+    let bangle = connect(&watch);
+    let content: Vec<u8> = (0..2000).map(|i| (i * 11 % 256) as u8).collect();
+    watch.set_file("log.bin", &content);
+    // one reply for the kind, 3 downloaded chunks, the size then 3 CRCs, re-read chunks
+    for (intact, count) in [(2, 1), (4, 1), (5, 1), (5, 2)] {
+        watch.garble_replies_after(intact, count);
+        assert_eq!(bangle.read_file("log.bin").await.unwrap(), content);
+    }
+}
+
+#[derive(Default)]
+struct Recorder(Mutex<Vec<(String, usize, usize)>>);
+
+impl Progress for Recorder {
+    fn update(&self, name: &str, done: usize, total: usize) {
+        self.0.lock().unwrap().push((name.to_string(), done, total));
+    }
+}
+
+#[tokio::test]
+async fn transfers_report_their_progress() {
+    let watch = FakeWatch::new();
+    let recorder = Arc::new(Recorder::default());
+    let bangle = connect(&watch).with_progress(recorder.clone());
+    let content: Vec<u8> = (0..5000).map(|i| (i * 7 % 256) as u8).collect();
+    watch.set_file("log.bin", &content);
+    bangle.read_file("log.bin").await.unwrap();
+    bangle.write_file("copy.bin", &content).await.unwrap();
+    let updates = recorder.0.lock().unwrap().clone();
+    for name in ["log.bin", "copy.bin"] {
+        let updates: Vec<_> = updates.iter().filter(|u| u.0 == name).collect();
+        assert!(updates.len() > 1);
+        assert!(updates.windows(2).all(|w| w[0].1 <= w[1].1));
+        let (_, done, total) = updates.last().unwrap();
+        assert_eq!(done, total);
+    }
+    // only received data counts for downloads, not the code we send
+    let first = updates.iter().find(|u| u.0 == "log.bin").unwrap();
+    assert_eq!((first.1, first.2), (768, 5000));
+    // uploads count bytes of the file too, not of the code carrying them
+    assert!(updates
+        .iter()
+        .filter(|u| u.0 == "copy.bin")
+        .all(|u| u.2 == 5000));
+}
+
+#[tokio::test]
+async fn storage_files_are_listed_read_written_and_erased() {
+    let watch = FakeWatch::new();
+    let log: Vec<u8> = (0..1000).map(|i| b"0123456789,\n"[i % 12]).collect();
+    watch.set_storage_file("health.csv", &log);
+    watch.set_file("app.js", b"1");
+    let bangle = connect(&watch);
+    assert_eq!(bangle.list_files().await.unwrap(), vec!["app.js"]);
+    assert_eq!(
+        bangle.list_storage_files().await.unwrap(),
+        vec!["health.csv"]
+    );
+    assert_eq!(bangle.read_file("health.csv").await.unwrap(), log);
+    bangle.write_storage_file("copy.csv", &log).await.unwrap();
+    assert_eq!(watch.storage_file("copy.csv"), log);
+    assert!(bangle
+        .write_storage_file("bad.bin", &[1, 255])
+        .await
+        .is_err());
+    bangle.erase("health.csv").await.unwrap();
+    bangle.erase("app.js").await.unwrap();
+    assert_eq!(bangle.list_storage_files().await.unwrap(), vec!["copy.csv"]);
+    assert!(bangle.list_files().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn file_infos_give_sizes_and_crcs() {
+    let watch = FakeWatch::new();
+    watch.set_file("app.js", b"hello");
+    watch.set_storage_file("log.csv", &[b'1'; 300]);
+    let bangle = connect(&watch);
+    let infos = bangle.list_file_infos(true).await.unwrap();
+    assert_eq!(
+        infos,
+        vec![
+            FileInfo {
+                name: "app.js".to_string(),
+                kind: FileKind::Plain,
+                size: 5,
+                crc: Some(crc32fast::hash(b"hello")),
+            },
+            FileInfo {
+                name: "log.csv".to_string(),
+                kind: FileKind::StorageFile,
+                size: 300,
+                crc: None,
+            },
+        ]
+    );
+    assert_eq!(bangle.list_file_infos(false).await.unwrap()[0].crc, None);
+}
+
+#[tokio::test]
+async fn compaction_empties_the_trash() {
+    let watch = FakeWatch::new();
+    let bangle = connect(&watch);
+    bangle.write_file("a.txt", &[b'a'; 100]).await.unwrap();
+    bangle.write_file("b.txt", &[b'b'; 50]).await.unwrap();
+    bangle.erase("a.txt").await.unwrap();
+    let stats = bangle.storage_stats().await.unwrap();
+    assert_eq!((stats.file_count, stats.file_bytes), (1, 50));
+    assert_eq!((stats.trash_count, stats.trash_bytes), (1, 100));
+    assert_eq!(stats.free_bytes, stats.total_bytes - 150);
+
+    let stats = bangle.compact().await.unwrap();
+    assert_eq!((stats.trash_count, stats.trash_bytes), (0, 0));
+    assert_eq!(stats.free_bytes, stats.total_bytes - 50);
+}
+
+#[tokio::test]
+async fn files_are_checked_against_expected_content() {
+    let watch = FakeWatch::new();
+    let bangle = connect(&watch).with_verification(false);
+    let content: Vec<u8> = (0..3000).map(|i| (i % 200) as u8).collect();
+    bangle.write_file("data.bin", &content).await.unwrap();
+    bangle.check_file("data.bin", &content).await.unwrap();
+
+    let mut changed = content.clone();
+    changed[2000] ^= 1;
+    let error = bangle.check_file("data.bin", &changed).await.unwrap_err();
+    assert!(error.to_string().contains("[1024]"), "{error}");
+    assert!(bangle.check_file("data.bin", &content[..10]).await.is_err());
+
+    watch.set_storage_file("log", b"a line\n");
+    bangle.check_file("log", b"a line\n").await.unwrap();
+}
+
+#[tokio::test]
+async fn files_are_copied_and_renamed_on_the_watch() {
+    let watch = FakeWatch::new();
+    let bangle = connect(&watch);
+    watch.set_file("a.txt", b"plain content");
+    let log: Vec<u8> = (0..1000).map(|i| b'a' + (i % 26) as u8).collect();
+    watch.set_storage_file("log", &log);
+
+    bangle.copy("a.txt", "b.txt", false).await.unwrap();
+    assert_eq!(watch.file("a.txt").unwrap(), b"plain content");
+    assert_eq!(watch.file("b.txt").unwrap(), b"plain content");
+    bangle.rename("log", "old.log", false).await.unwrap();
+    assert_eq!(watch.storage_file("old.log"), log);
+    assert!(watch.storage_file("log").is_empty());
+
+    let error = bangle.rename("a.txt", "b.txt", false).await.unwrap_err();
+    assert!(error.to_string().contains("already exists"), "{error}");
+    watch.set_file("b.txt", b"other content");
+    bangle.rename("b.txt", "a.txt", true).await.unwrap();
+    assert_eq!(watch.file("a.txt").unwrap(), b"other content");
+    assert_eq!(watch.file("b.txt"), None);
+
+    let long_name = "x".repeat(28);
+    assert!(bangle.copy("a.txt", &long_name, false).await.is_ok());
+    assert!(bangle.copy("old.log", &long_name, true).await.is_err());
+}
+
+#[tokio::test]
+async fn filenames_with_quotes_are_escaped() {
+    let watch = FakeWatch::new();
+    let bangle = connect(&watch);
+    let name = r#"a"b\c.txt"#;
+    bangle.write_file(name, b"content").await.unwrap();
+    assert_eq!(watch.file(name).unwrap(), b"content");
+    assert_eq!(bangle.read_file(name).await.unwrap(), b"content");
+    bangle.copy(name, r#"d"e"#, false).await.unwrap();
+    assert_eq!(watch.file(r#"d"e"#).unwrap(), b"content");
+    bangle.erase(name).await.unwrap();
+    assert_eq!(watch.file(name), None);
+}
+
+#[tokio::test]
+async fn queued_requests_wait_for_the_running_one() {
+    let watch = FakeWatch::new()
+        .with_packet_size(256)
+        .with_latency(Duration::from_millis(10));
+    watch.set_file("big.bin", &[7; 20000]);
+    let bangle = connect(&watch)
+        .with_timeout(Duration::from_millis(200))
+        .with_verification(false);
+    // the download takes several timeouts but the watch keeps talking
+    let queued = async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        bangle.eval("1 + 1").await
+    };
+    let (content, value) = tokio::join!(bangle.read_file("big.bin"), queued);
+    assert_eq!(content.unwrap(), vec![7; 20000]);
+    assert_eq!(value.unwrap(), "2");
+}
+
+#[tokio::test]
+async fn backups_are_restored() {
+    let watch = FakeWatch::new();
+    let log: Vec<u8> = (0..600).map(|i| b"12,34\n"[i % 6]).collect();
+    watch.set_file("app.js", b"print(1)");
+    watch.set_file("app.json", b"{}");
+    watch.set_file("empty", b"");
+    watch.set_storage_file("log", &log);
+    let bangle = connect(&watch);
+    let directory = tempfile::tempdir().unwrap();
+    for name in ["backup.tar", "backup.zip"] {
+        let archive = directory.path().join(name);
+        let archive = archive.to_str().unwrap();
+        let all = Selection::new(&[], &[]).unwrap();
+        let saved = archive::backup(&bangle, archive, &all).await.unwrap();
+        assert_eq!(saved.len(), 4);
+
+        let restored = FakeWatch::new();
+        let without_json = Selection::new(&[], &["*.json".to_string()]).unwrap();
+        let report = archive::restore(&connect(&restored), archive, &without_json)
+            .await
+            .unwrap();
+        assert_eq!(report.files.len(), 2);
+        assert_eq!(report.skipped, ["empty"]);
+        assert!(report.failed.is_empty());
+        assert_eq!(restored.file("app.js").unwrap(), b"print(1)");
+        assert_eq!(restored.storage_file("log"), log);
+        assert_eq!(restored.file("app.json"), None);
+        assert_eq!(restored.file("empty"), None);
+    }
+}
+
+#[tokio::test]
+async fn damaged_backups_are_not_restored() {
+    let watch = FakeWatch::new();
+    watch.set_file("app.js", b"print(1)");
+    watch.set_file("log.txt", b"12,34\n12,34\n");
+    let directory = tempfile::tempdir().unwrap();
+    let archive = directory.path().join("backup.tar");
+    let archive = archive.to_str().unwrap();
+    let all = Selection::new(&[], &[]).unwrap();
+    archive::backup(&connect(&watch), archive, &all)
+        .await
+        .unwrap();
+    // tar doesn't check the contents of its entries
+    let mut content = std::fs::read(archive).unwrap();
+    let at = content.windows(6).rposition(|w| w == b"12,34\n").unwrap();
+    content[at + 4] = b'5';
+    std::fs::write(archive, content).unwrap();
+
+    let restored = FakeWatch::new();
+    let error = archive::restore(&connect(&restored), archive, &all)
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("log.txt is corrupted"));
+    assert!(restored.files().is_empty());
+}
+
+#[tokio::test]
+async fn stream_links_carry_requests() {
+    let (host, watch) = tokio::io::duplex(1024);
+    let (reader, writer) = tokio::io::split(host);
+    let bangle = Bangle::new(Box::new(StreamTransport::new(reader, writer, 64)));
+    // a minimal espruino, echoing back what `console.log('\x10...')` would print
+    tokio::spawn(async move {
+        let (reader, mut writer) = tokio::io::split(watch);
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            let Some((_, tag)) = line.split_once("console.log('\\x10") else {
+                continue;
+            };
+            let answer = match tag.split_once("')") {
+                // the end token
+                Some((token, _)) => token.to_string(),
+                // the reply of `1 + 1`
+                None => format!("{}2", tag.split_once('\'').unwrap().0),
+            };
+            let answer = format!("\x10{answer}\r\n");
+            writer.write_all(answer.as_bytes()).await.unwrap();
+        }
+    });
+    assert_eq!(bangle.eval("1 + 1").await.unwrap(), "2");
+}
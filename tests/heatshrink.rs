@@ -0,0 +1,19 @@
+use banglecomm::heatshrink;
+
+const CODE: &[u8] = b"Bangle.setLCDPower(1);Bangle.setLCDPower(0);g.clear();g.clear();";
+/// `CODE` compressed by the reference heatshrink encoder with espruino's settings
+/// (window 8, lookahead 4).
+const COMPRESSED: &[u8] = &[
+    161, 88, 109, 214, 123, 101, 150, 93, 115, 178, 221, 41, 148, 58, 37, 66, 223, 119, 178, 220,
+    165, 19, 25, 76, 236, 43, 225, 82, 152, 5, 70, 207, 46, 177, 133, 6, 194, 10, 16, 77, 128,
+];
+
+#[test]
+fn reference_stream_is_decompressed() {
+    assert_eq!(heatshrink::decompress(COMPRESSED), CODE);
+}
+
+#[test]
+fn compression_matches_the_reference_encoder() {
+    assert_eq!(heatshrink::compress(CODE), COMPRESSED);
+}